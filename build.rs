@@ -0,0 +1,12 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+fn main() {
+    // the target triple selects the platform of prebuilt binaries
+    println!(
+        "cargo:rustc-env=TARGET={}",
+        std::env::var("TARGET").expect("TARGET is set by cargo")
+    );
+}
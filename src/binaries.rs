@@ -0,0 +1,110 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Executables installed by modules live in the `bin` directory of terminal-magic, which the
+//! `env` file adds to the `PATH`. `binaries.toml` records which module owns which executable.
+
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use indexmap::IndexMap;
+
+use crate::{
+    filesystem,
+    models::{to_toml_string, GlobalConfig},
+};
+
+pub static BIN_DIR: &str = "bin";
+pub static REGISTRY_FILE: &str = "binaries.toml";
+
+/// The owning module by executable name.
+pub fn read_registry(home: &Path) -> IndexMap<String, String> {
+    std::fs::read_to_string(home.join(REGISTRY_FILE))
+        .ok()
+        .and_then(|registry| toml::from_str(&registry).ok())
+        .unwrap_or_default()
+}
+
+fn write_registry(global_config: &GlobalConfig, registry: &IndexMap<String, String>) {
+    if filesystem::write(
+        global_config,
+        &global_config.home.join(REGISTRY_FILE),
+        to_toml_string(registry).expect("could not serialize binaries"),
+    )
+    .is_err()
+    {
+        eprintln!("{}", "Could not write the binary registry".red());
+    }
+}
+
+pub fn binary_path(home: &Path, name: &str) -> PathBuf {
+    home.join(BIN_DIR).join(name)
+}
+
+/// Describes every executable which is already owned by another module.
+pub fn binary_conflicts(
+    global_config: &GlobalConfig,
+    plugin_name: &str,
+    names: &[String],
+) -> Vec<String> {
+    let registry = read_registry(&global_config.home);
+    names
+        .iter()
+        .filter_map(|name| match registry.get(name) {
+            Some(owner) if owner != plugin_name => Some(format!(
+                "executable {} is already installed by {}",
+                name, owner
+            )),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Records executables the module put into the `bin` directory itself, e.g. with `cargo install`.
+pub fn register_binaries(global_config: &GlobalConfig, plugin_name: &str, names: &[String]) {
+    let mut registry = read_registry(&global_config.home);
+    for name in names {
+        registry.insert(name.to_owned(), plugin_name.to_string());
+    }
+    write_registry(global_config, &registry);
+}
+
+/// Writes an executable into the `bin` directory and records it as owned by the module.
+pub fn install_binary(
+    global_config: &GlobalConfig,
+    plugin_name: &str,
+    name: &str,
+    contents: &[u8],
+    mode: u32,
+) -> std::io::Result<()> {
+    let path = binary_path(&global_config.home, name);
+    filesystem::create_dir_all(global_config, &global_config.home.join(BIN_DIR))?;
+    filesystem::write(global_config, &path, contents)?;
+    filesystem::set_mode(global_config, &path, mode)?;
+    register_binaries(global_config, plugin_name, &[name.to_string()]);
+    Ok(())
+}
+
+/// Removes the executables owned by the module, except the ones to `keep`.
+pub fn remove_binaries(global_config: &GlobalConfig, plugin_name: &str, keep: &[String]) {
+    let home = &global_config.home;
+    let mut registry = read_registry(home);
+    let owned: Vec<String> = registry
+        .iter()
+        .filter(|(name, owner)| *owner == plugin_name && !keep.contains(name))
+        .map(|(name, _)| name.to_owned())
+        .collect();
+    if owned.is_empty() {
+        return;
+    }
+    for name in owned {
+        let path = binary_path(home, &name);
+        if path.exists() && filesystem::remove_file(global_config, &path).is_err() {
+            eprintln!("{} {}", "Could not remove executable".red(), name);
+        }
+        registry.shift_remove(&name);
+    }
+    write_registry(global_config, &registry);
+}
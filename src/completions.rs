@@ -0,0 +1,161 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Completion files declared in the `[completions]` table of a module are installed into
+//! `completion/` (zsh) and `completion/<shell>/` (bash, fish). `completions.toml` records which
+//! module owns which file, so modules can't overwrite each other's completions and `remove` knows
+//! what to delete.
+
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use indexmap::IndexMap;
+
+use crate::{
+    filesystem,
+    models::{to_toml_string, Completions, GlobalConfig},
+};
+
+pub static COMPLETION_DIR: &str = "completion";
+pub static REGISTRY_FILE: &str = "completions.toml";
+
+/// The owning module by installed completion, keyed as `<shell>/<name>`.
+pub fn read_registry(home: &Path) -> IndexMap<String, String> {
+    std::fs::read_to_string(home.join(REGISTRY_FILE))
+        .ok()
+        .and_then(|registry| toml::from_str(&registry).ok())
+        .unwrap_or_default()
+}
+
+fn write_registry(global_config: &GlobalConfig, registry: &IndexMap<String, String>) {
+    if filesystem::write(
+        global_config,
+        &global_config.home.join(REGISTRY_FILE),
+        to_toml_string(registry).expect("could not serialize completions"),
+    )
+    .is_err()
+    {
+        eprintln!("{}", "Could not write the completion registry".red());
+    }
+}
+
+/// Where the completion `key` (`<shell>/<name>`) is installed.
+pub fn completion_path(home: &Path, key: &str) -> PathBuf {
+    match key.split_once('/') {
+        // zsh completions stay directly in the directory added to FPATH
+        Some(("zsh", name)) => home.join(COMPLETION_DIR).join(name),
+        _ => home.join(COMPLETION_DIR).join(key),
+    }
+}
+
+fn completion_key(shell: &str, path: &str) -> String {
+    let name = Path::new(path)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| path.to_string());
+    format!("{}/{}", shell, name)
+}
+
+/// Describes every declared completion which is already owned by another module.
+pub fn completion_conflicts(
+    global_config: &GlobalConfig,
+    plugin_name: &str,
+    completions: Option<&Completions>,
+) -> Vec<String> {
+    let registry = read_registry(&global_config.home);
+    completions
+        .into_iter()
+        .flat_map(Completions::iter)
+        .filter_map(|(shell, path)| {
+            let key = completion_key(shell, path);
+            match registry.get(&key) {
+                Some(owner) if owner != plugin_name => Some(format!(
+                    "{} completion {} is already installed by {}",
+                    shell,
+                    key.split_once('/').unwrap().1,
+                    owner
+                )),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Installs the declared completions of the module and removes the ones it no longer declares.
+pub fn install_completions(
+    global_config: &GlobalConfig,
+    plugin_name: &str,
+    completions: Option<&Completions>,
+    path_to_module: &Path,
+) {
+    let home = &global_config.home;
+    let mut registry = read_registry(home);
+    let declared: IndexMap<String, &String> = completions
+        .into_iter()
+        .flat_map(Completions::iter)
+        .map(|(shell, path)| (completion_key(shell, path), path))
+        .collect();
+    let stale: Vec<String> = registry
+        .iter()
+        .filter(|(key, owner)| *owner == plugin_name && !declared.contains_key(*key))
+        .map(|(key, _)| key.to_owned())
+        .collect();
+    for key in stale {
+        let _ = filesystem::remove_file(global_config, &completion_path(home, &key));
+        registry.shift_remove(&key);
+    }
+    for (key, path) in declared {
+        match registry.get(&key) {
+            Some(owner) if owner != plugin_name => {
+                eprintln!(
+                    "{} {} {}",
+                    "Skipping completion".red(),
+                    key,
+                    format!("owned by {}", owner).red()
+                );
+                continue;
+            }
+            _ => {}
+        }
+        let destination = completion_path(home, &key);
+        if let Some(parent) = destination.parent() {
+            let _ = filesystem::create_dir_all(global_config, parent);
+        }
+        let source = path_to_module.join(path);
+        if let Err(err) = filesystem::copy(global_config, &source, &destination) {
+            eprintln!(
+                "{} {:?}: {:?}",
+                "Could not install completion".red(),
+                source,
+                err
+            );
+            continue;
+        }
+        registry.insert(key, plugin_name.to_string());
+    }
+    write_registry(global_config, &registry);
+}
+
+/// Removes the completions owned by the module. Returns whether it had any.
+pub fn remove_completions(global_config: &GlobalConfig, plugin_name: &str) -> bool {
+    let home = &global_config.home;
+    let mut registry = read_registry(home);
+    let owned: Vec<String> = registry
+        .iter()
+        .filter(|(_, owner)| *owner == plugin_name)
+        .map(|(key, _)| key.to_owned())
+        .collect();
+    if owned.is_empty() {
+        return false;
+    }
+    for key in owned {
+        if filesystem::remove_file(global_config, &completion_path(home, &key)).is_err() {
+            eprintln!("{} {}", "Could not remove completion".red(), key);
+        }
+        registry.shift_remove(&key);
+    }
+    write_registry(global_config, &registry);
+    true
+}
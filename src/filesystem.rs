@@ -0,0 +1,144 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Filesystem operations which only print what they would do when `--dry-run` is set.
+
+use std::{
+    io::{Error, ErrorKind, Write},
+    path::Path,
+    process::{Command, ExitStatus},
+};
+
+use colored::Colorize;
+use sha2::{Digest, Sha256};
+
+use crate::models::GlobalConfig;
+
+fn plan(action: &str, path: &Path) {
+    println!(
+        "{} {} {}",
+        "[dry-run]".yellow(),
+        action,
+        path.to_string_lossy()
+    );
+}
+
+pub fn create_dir_all(global_config: &GlobalConfig, path: &Path) -> std::io::Result<()> {
+    if global_config.dry_run {
+        if !path.exists() {
+            plan("create directory", path);
+        }
+        return Ok(());
+    }
+    std::fs::create_dir_all(path)
+}
+
+pub fn copy(global_config: &GlobalConfig, from: &Path, to: &Path) -> std::io::Result<u64> {
+    if global_config.dry_run {
+        plan(&format!("copy {} to", from.to_string_lossy()), to);
+        return Ok(0);
+    }
+    std::fs::copy(from, to)
+}
+
+pub fn write<C: AsRef<[u8]>>(
+    global_config: &GlobalConfig,
+    path: &Path,
+    contents: C,
+) -> std::io::Result<()> {
+    if global_config.dry_run {
+        plan("write", path);
+        return Ok(());
+    }
+    std::fs::write(path, contents)
+}
+
+/// Like `write`, but the file is only readable by the user.
+pub fn write_private<C: AsRef<[u8]>>(
+    global_config: &GlobalConfig,
+    path: &Path,
+    contents: C,
+) -> std::io::Result<()> {
+    if global_config.dry_run {
+        plan("write (mode 0600)", path);
+        return Ok(());
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents.as_ref())
+}
+
+/// Sets the permission bits of `path`, e.g. `0o755`. Does nothing on platforms without them.
+pub fn set_mode(global_config: &GlobalConfig, path: &Path, mode: u32) -> std::io::Result<()> {
+    if global_config.dry_run {
+        plan(&format!("set mode {:o} of", mode), path);
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        std::fs::set_permissions(path, std::os::unix::fs::PermissionsExt::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(())
+    }
+}
+
+pub fn symlink(global_config: &GlobalConfig, original: &Path, link: &Path) -> std::io::Result<()> {
+    if global_config.dry_run {
+        plan(&format!("link {} at", original.to_string_lossy()), link);
+        return Ok(());
+    }
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(original, link)
+    }
+    #[cfg(not(unix))]
+    {
+        Err(Error::new(
+            ErrorKind::Unsupported,
+            "Symlinks are only supported on unix",
+        ))
+    }
+}
+
+/// Behaves like `std::fs::remove_file`, in dry-run mode it fails if there is nothing to remove.
+pub fn remove_file(global_config: &GlobalConfig, path: &Path) -> std::io::Result<()> {
+    if global_config.dry_run {
+        if !path.exists() {
+            return Err(Error::from(ErrorKind::NotFound));
+        }
+        plan("remove file", path);
+        return Ok(());
+    }
+    std::fs::remove_file(path)
+}
+
+pub fn remove_dir_all(global_config: &GlobalConfig, path: &Path) -> std::io::Result<()> {
+    if global_config.dry_run {
+        plan("remove directory", path);
+        return Ok(());
+    }
+    std::fs::remove_dir_all(path)
+}
+
+/// Runs `command` with inherited stdio, or just prints it in dry-run mode.
+pub fn run(global_config: &GlobalConfig, command: &mut Command) -> std::io::Result<ExitStatus> {
+    if global_config.dry_run {
+        println!("{} run {:?}", "[dry-run]".yellow(), command);
+        return Ok(ExitStatus::default());
+    }
+    command.status()
+}
+
+/// Hex encoded sha256 of `contents`.
+pub fn sha256(contents: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(contents))
+}
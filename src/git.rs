@@ -10,6 +10,7 @@ use colored::Colorize;
 use git2::{Commit, Cred, Error, ObjectType, RemoteCallbacks, Repository};
 
 use crate::{
+    filesystem,
     models::GlobalConfig,
     prompts::{boolean_prompt, password_prompt, text_prompt},
 };
@@ -18,7 +19,7 @@ pub fn get_callbacks(
     global_config: &mut GlobalConfig,
     ssh_key: Option<PathBuf>,
     key_needs_pw: bool,
-) -> RemoteCallbacks {
+) -> RemoteCallbacks<'_> {
     update_git_repo_path(global_config).expect("Could not create git repo directory");
     let mut callbacks = RemoteCallbacks::new();
     callbacks.credentials(move |_url, username_from_url, allowed_types| {
@@ -100,7 +101,7 @@ pub fn update_git_repo_path(global_config: &mut GlobalConfig) -> Result<(), Erro
         return Ok(());
     }
     if !global_config.home.join("git_modules").exists() {
-        match std::fs::create_dir_all(global_config.home.join("git_modules")) {
+        match filesystem::create_dir_all(global_config, &global_config.home.join("git_modules")) {
             Ok(_) => {}
             Err(_) => {
                 eprintln!("Could not create git_modules");
@@ -113,7 +114,7 @@ pub fn update_git_repo_path(global_config: &mut GlobalConfig) -> Result<(), Erro
         .join("git_modules")
         .to_string_lossy()
         .to_string();
-    if global_config.save().is_err() {
+    if !global_config.dry_run && global_config.save().is_err() {
         eprintln!("{}", "Could not write config".red());
     }
     Ok(())
@@ -183,7 +184,7 @@ pub fn fast_forward(repo: &Repository, branch: &str) -> Result<(), Error> {
         Err(Error::from_str("Fast-forward only!"))
     }
 }
-pub fn find_last_commit(repo: &Repository) -> Result<Commit, git2::Error> {
+pub fn find_last_commit(repo: &Repository) -> Result<Commit<'_>, git2::Error> {
     let obj = repo.head()?.resolve()?.peel(ObjectType::Commit)?;
     match obj.into_commit() {
         Ok(c) => Ok(c),
@@ -0,0 +1,145 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Lifecycle hooks declared in the `[hooks]` table of a module config. Hooks are rendered like
+//! the template, shown and confirmed before they run, and abort the operation if they fail.
+
+use std::process::Command;
+
+use colored::Colorize;
+
+use crate::{
+    filesystem,
+    models::{GlobalConfig, PluginInfo},
+    prompts::boolean_prompt,
+    secrets::{installed_secret_values, mask},
+    template::{compile_template, render},
+};
+
+pub static HOOKS: [&str; 5] = [
+    "pre_install",
+    "post_install",
+    "pre_update",
+    "post_update",
+    "pre_remove",
+];
+
+/// The rendered `pre_remove` hook is kept with the installed module, so it can run even if the
+/// module is gone from the git repo.
+pub static PRE_REMOVE_FILE: &str = "pre_remove.sh";
+
+/// Renders the `hook` of the module, if it declares one.
+pub fn render_hook(
+    git_repo: &str,
+    plugin_name: &str,
+    config: &PluginInfo,
+    hook: &str,
+    data: &mustache::Data,
+) -> Option<String> {
+    let path = config.hooks.as_ref()?.get(hook)?;
+    let mustache = compile_template(git_repo, plugin_name, path, config.plugin_info.unescaped);
+    Some(render(mustache, data))
+}
+
+/// Shows the hook and runs it after confirmation. Returns `false` if it was declined or failed.
+pub fn run_hook(
+    global_config: &GlobalConfig,
+    plugin_name: &str,
+    hook: &str,
+    script: &str,
+    secrets: &[String],
+) -> bool {
+    println!("[{}] {} hook:", plugin_name.yellow(), hook);
+    println!("{}", mask(script, secrets));
+    if !global_config.dry_run && !boolean_prompt(&format!("Run the {} hook?", hook)) {
+        eprintln!("{}", format!("{} hook declined", hook).red());
+        return false;
+    }
+    // honor the interpreter of a shebang, every common one accepts `-c`
+    let mut interpreter: Vec<&str> = script
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix("#!"))
+        .map(|line| line.split_whitespace().collect())
+        .unwrap_or_default();
+    if interpreter.is_empty() {
+        interpreter.push("sh");
+    }
+    let mut command = Command::new(interpreter[0]);
+    command
+        .args(&interpreter[1..])
+        .arg("-c")
+        .arg(script)
+        .env("TM_MODULE", plugin_name)
+        .env("TM_HOME", &global_config.home)
+        .env("TM_MODULE_HOME", global_config.home.join(plugin_name));
+    match filesystem::run(global_config, &mut command) {
+        Ok(status) if status.success() => {
+            println!("{}", format!("{} hook finished", hook).green());
+            true
+        }
+        Ok(status) => {
+            eprintln!("{}", format!("{} hook failed ({})", hook, status).red());
+            false
+        }
+        Err(err) => {
+            eprintln!(
+                "{}",
+                format!("Could not run the {} hook: {}", hook, err).red()
+            );
+            false
+        }
+    }
+}
+
+/// Renders and runs the `hook` of the module. Modules without the hook always succeed.
+pub fn run_module_hook(
+    global_config: &GlobalConfig,
+    git_repo: &str,
+    plugin_name: &str,
+    config: &PluginInfo,
+    hook: &str,
+    data: &mustache::Data,
+    secrets: &[String],
+) -> bool {
+    match render_hook(git_repo, plugin_name, config, hook, data) {
+        Some(script) => run_hook(global_config, plugin_name, hook, &script, secrets),
+        None => true,
+    }
+}
+
+/// Stores the rendered `pre_remove` hook with the installed module, or removes a stale one.
+pub fn save_pre_remove_hook(
+    global_config: &GlobalConfig,
+    git_repo: &str,
+    plugin_name: &str,
+    config: &PluginInfo,
+    data: &mustache::Data,
+) {
+    let path = global_config.home.join(plugin_name).join(PRE_REMOVE_FILE);
+    let written = match render_hook(git_repo, plugin_name, config, "pre_remove", data) {
+        Some(script) => filesystem::write_private(global_config, &path, script),
+        None if path.exists() => filesystem::remove_file(global_config, &path),
+        None => Ok(()),
+    };
+    if written.is_err() {
+        eprintln!("{}", "Could not write the pre_remove hook".red());
+    }
+}
+
+/// Runs the stored `pre_remove` hook of an installed module.
+pub fn run_pre_remove_hook(global_config: &GlobalConfig, plugin_name: &str) -> bool {
+    let home_path = global_config.home.join(plugin_name);
+    match std::fs::read_to_string(home_path.join(PRE_REMOVE_FILE)) {
+        Ok(script) => run_hook(
+            global_config,
+            plugin_name,
+            "pre_remove",
+            &script,
+            &installed_secret_values(&home_path),
+        ),
+        Err(_) => true,
+    }
+}
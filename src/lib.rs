@@ -3,8 +3,13 @@
 // This software is released under the MIT License.
 // https://opensource.org/licenses/MIT
 
+pub mod binaries;
+pub mod completions;
+pub mod filesystem;
 pub mod modules;
 pub mod git;
+pub mod hooks;
 pub mod models;
 pub mod prompts;
+pub mod secrets;
 pub mod template;
\ No newline at end of file
@@ -6,13 +6,24 @@ use std::{
     path::{Path, PathBuf},
     str::FromStr,
 };
-use structopt::StructOpt;
+use structopt::{
+    clap::{AppSettings, Shell},
+    StructOpt,
+};
 use terminal_magic::{
     git::{check_out_modules_with_key, check_out_modules_with_pw, update_modules},
     models::{GlobalConfig, PluginInfo, CONFIG_DIR},
+    secrets::{installed_secret_values, mask, mask_placeholders},
     modules::{
-        get_list_of_installed_modules, install::install, read_config, read_dir, remove,
-        update::update, update_source_file,
+        get_list_of_available_modules, get_list_of_installed_modules,
+        install::install,
+        new::new_module,
+        profile::profile,
+        read_config, read_dir, remove, set_enabled,
+        render::{diff_module, render_module},
+        update::update,
+        validate::validate,
+        update_source_file,
     },
 };
 
@@ -37,49 +48,288 @@ struct TerminalMagicArgs {
     clone: Option<String>,
     #[structopt(help = "Ssh key to clone repository", short = "s", long = "ssh_key")]
     ssh_key: Option<String>,
+    #[structopt(
+        help = "Only print the filesystem operations install, update and remove would perform",
+        long = "dry-run"
+    )]
+    dry_run: bool,
+    #[structopt(
+        help = "Render templates even if they use variables without a value, install and update abort otherwise",
+        long = "no-strict"
+    )]
+    no_strict: bool,
     #[structopt(subcommand)]
     subcommand: Option<TerminalMagicAction>,
 }
 #[derive(StructOpt)]
 pub enum TerminalMagicAction {
     Install(InstallArgs),
+    New(NewArgs),
     Remove(RemoveArgs),
     Update(UpdateArgs),
+    Reconfigure(ReconfigureArgs),
+    Render(RenderArgs),
+    Diff(DiffArgs),
     List(ListArgs),
+    Enable(EnableArgs),
+    Profile(ProfileArgs),
+    Disable(DisableArgs),
+    Validate(ValidateArgs),
+    Completions(CompletionsArgs),
+    #[structopt(setting = AppSettings::Hidden)]
+    Modules(ModulesArgs),
 }
 
 #[derive(StructOpt)]
 #[structopt(about = "Install new extension. Use path from Git Repo as name")]
 pub struct InstallArgs {
+    #[structopt(name = "module")]
+    input: String,
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Create a new extension in the Git Repo. Use the path as name, e.g. zsh/docker")]
+pub struct NewArgs {
+    #[structopt(name = "path")]
     input: String,
 }
 
 #[derive(StructOpt)]
 #[structopt(about = "Update new extension.")]
 pub struct UpdateArgs {
+    #[structopt(name = "installed-module")]
+    input: String,
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Re-enter all placeholders of an installed extension.")]
+pub struct ReconfigureArgs {
+    #[structopt(name = "installed-module")]
+    input: String,
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Print the script an update would produce, without writing anything.")]
+pub struct RenderArgs {
+    #[structopt(name = "installed-module")]
+    input: String,
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Show the diff between the installed extension and the git repo.")]
+pub struct DiffArgs {
+    #[structopt(name = "installed-module")]
     input: String,
 }
 
 #[derive(StructOpt)]
 #[structopt(about = "Remove extension. Use path from Git Repo as name")]
 pub struct RemoveArgs {
+    #[structopt(name = "installed-module")]
     input: String,
 }
 
 #[derive(StructOpt)]
 #[structopt(about = "List available modules")]
 pub struct ListArgs {
+    #[structopt(name = "module")]
     input: Option<String>,
 }
 
+#[derive(StructOpt)]
+#[structopt(about = "Source a disabled extension again.")]
+pub struct EnableArgs {
+    #[structopt(name = "installed-module")]
+    input: String,
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Stop sourcing an extension, but keep it installed.")]
+pub struct DisableArgs {
+    #[structopt(name = "installed-module")]
+    input: String,
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Time sourcing each installed extension and rank them.")]
+pub struct ProfileArgs {
+    #[structopt(
+        help = "Shell to source the extensions with",
+        long = "shell",
+        default_value = "zsh"
+    )]
+    shell: String,
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Check all modules of the Git Repo, e.g. in CI.")]
+pub struct ValidateArgs {
+    #[structopt(
+        name = "path",
+        help = "Module repo to check instead of the configured one, needs no global config"
+    )]
+    path: Option<String>,
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Print the shell completions for terminal-magic")]
+pub struct CompletionsArgs {
+    #[structopt(possible_values = &["zsh", "bash", "fish"])]
+    shell: Shell,
+}
+
+#[derive(StructOpt)]
+#[structopt(about = "Print the names of the available modules, used by the shell completions")]
+pub struct ModulesArgs {
+    #[structopt(help = "Only print installed modules", long = "installed")]
+    installed: bool,
+}
+
+/// Subcommands with `AppSettings::Hidden`, which clap still lists in the completions.
+static HIDDEN_SUBCOMMANDS: &[&str] = &["modules"];
+
+/// Drops the hidden subcommands from the lists of subcommands in a generated script.
+fn hide_subcommands(script: &str) -> String {
+    script
+        .lines()
+        .filter(|line| {
+            !HIDDEN_SUBCOMMANDS.iter().any(|name| {
+                // zsh lists `"name:about"`, fish `-a "name" -d 'about'`
+                line.starts_with(&format!("\"{}:", name))
+                    || line.contains(&format!("\"__fish_use_subcommand\" -f -a \"{}\"", name))
+            })
+        })
+        .map(|line| {
+            // bash lists the subcommands in the options of the top level command
+            if line.trim_start().starts_with("opts=") {
+                line.split(' ')
+                    .filter(|word| !HIDDEN_SUBCOMMANDS.contains(word))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            } else {
+                line.to_string()
+            }
+        })
+        .map(|line| line + "\n")
+        .collect()
+}
+
+/// The completions generated by clap, extended to complete module names by calling
+/// `terminal-magic modules` and to also apply to the `tm` alias.
+fn completion_script(shell: Shell) -> String {
+    let mut script = vec![];
+    TerminalMagicArgs::clap().gen_completions_to("terminal-magic", shell, &mut script);
+    let script =
+        hide_subcommands(&String::from_utf8(script).expect("Completions are not valid UTF-8"));
+    match shell {
+        Shell::Zsh => script
+            .replacen("#compdef terminal-magic", "#compdef terminal-magic tm", 1)
+            .replace(":module:_files'", ":module:_terminal-magic_modules'")
+            .replace(
+                ":installed-module:_files'",
+                ":installed-module:_terminal-magic_installed_modules'",
+            )
+            .replace(
+                "\n_terminal-magic \"$@\"",
+                r#"
+(( $+functions[_terminal-magic_modules] )) ||
+_terminal-magic_modules() {
+    local modules; modules=(${(f)"$(terminal-magic modules 2>/dev/null)"})
+    _describe -t modules 'modules' modules
+}
+(( $+functions[_terminal-magic_installed_modules] )) ||
+_terminal-magic_installed_modules() {
+    local modules; modules=(${(f)"$(terminal-magic modules --installed 2>/dev/null)"})
+    _describe -t modules 'installed modules' modules
+}
+
+_terminal-magic "$@""#,
+            ),
+        Shell::Bash => {
+            script
+                .replacen("terminal-magic)", "terminal-magic|tm)", 1)
+                // clap names the cases after the binary with `-` replaced, but not `cmd`
+                .replace("cmd=\"terminal-magic\"", "cmd=\"terminal__magic\"")
+                .replace("\n        terminal-magic)\n", "\n        terminal__magic)\n")
+                .replace("<module>", "$(terminal-magic modules 2>/dev/null)")
+                .replace(
+                    "<installed-module>",
+                    "$(terminal-magic modules --installed 2>/dev/null)",
+                )
+                + "complete -F _terminal-magic -o bashdefault -o default tm\n"
+        }
+        Shell::Fish => {
+            script
+                + r#"complete -c terminal-magic -n "__fish_seen_subcommand_from install list" -f -a "(terminal-magic modules 2>/dev/null)"
+complete -c terminal-magic -n "__fish_seen_subcommand_from remove update reconfigure render diff enable disable" -f -a "(terminal-magic modules --installed 2>/dev/null)"
+complete -c tm -w terminal-magic
+"#
+        }
+        _ => script,
+    }
+}
+
 fn main() {
     let cli_args = TerminalMagicArgs::from_args();
 
-    let mut global_config = GlobalConfig::default();
+    if let Some(TerminalMagicAction::Validate(ValidateArgs { path: Some(path) })) =
+        &cli_args.subcommand
+    {
+        std::process::exit(if validate(&shellexpand::tilde(path)) { 0 } else { 1 });
+    }
+
+    // needs no config, so generating a script doesn't create or change one
+    if let Some(TerminalMagicAction::Completions(completions_args)) = &cli_args.subcommand {
+        print!("{}", completion_script(completions_args.shell));
+        std::process::exit(0);
+    }
+
+    let mut global_config = GlobalConfig {
+        dry_run: cli_args.dry_run,
+        strict: !cli_args.no_strict,
+        ..GlobalConfig::default()
+    };
 
     if let Some(git_repo) = cli_args.git_repo {
         global_config.git_repo = shellexpand::tilde(&git_repo).to_string();
-        global_config.save().expect("Could not save global config");
+        if !global_config.dry_run {
+            global_config.save().expect("Could not save global config");
+        }
+    }
+
+    // prints machine readable output, so it runs before anything is printed
+    if let Some(TerminalMagicAction::Modules(modules_args)) = &cli_args.subcommand {
+        let modules = if modules_args.installed {
+            get_list_of_installed_modules(
+                &global_config.home,
+                &global_config.home.to_string_lossy(),
+            )
+            .unwrap_or_default()
+            .into_iter()
+            .map(|module| module.replace("/script.sh", ""))
+            .collect()
+        } else {
+            get_list_of_available_modules(
+                Path::new(&global_config.git_repo),
+                &global_config.git_repo,
+            )
+        };
+        for module in modules {
+            println!("{}", module);
+        }
+        std::process::exit(0);
+    }
+
+    // these work on the checkout as it is, so they run before the git repo is updated
+    match &cli_args.subcommand {
+        Some(TerminalMagicAction::Validate(_)) => {
+            std::process::exit(if validate(&global_config.git_repo) { 0 } else { 1 });
+        }
+        Some(TerminalMagicAction::New(new_args)) => {
+            new_module(&global_config, &global_config.git_repo, &new_args.input);
+            std::process::exit(0);
+        }
+        _ => {}
     }
 
     let git_repo = global_config.git_repo.to_string();
@@ -87,7 +337,9 @@ fn main() {
     println!();
 
     // save the config just generally everytime
-    let _ = global_config.save();
+    if !global_config.dry_run {
+        let _ = global_config.save();
+    }
 
     if let Some(clone_url) = &cli_args.clone {
         if let Some(ssh_key) = &cli_args.ssh_key {
@@ -209,7 +461,7 @@ fn main() {
                     }
                     println!("Placeholders: ");
                     if let Some(placeholders) = &config.placeholders {
-                        print!("{}", format!("{:?}", placeholders).green());
+                        print!("{}", format!("{:?}", mask_placeholders(placeholders)).green());
                     }
                     std::process::exit(0);
                 }
@@ -259,7 +511,7 @@ fn main() {
                                 old_version,
                                 new_version
                             );
-                            update(&global_config, &git_repo, &module, false, true);
+                            update(&global_config, &git_repo, &module, false, true, false);
                         }
                     }
                 }
@@ -268,10 +520,57 @@ fn main() {
                     "\n 🥳 All updateable packages are up to date.\n".green()
                 );
             } else {
-                update(&global_config, &git_repo, plugin_name, true, false);
+                update(&global_config, &git_repo, plugin_name, true, false, false);
+            }
+        }
+        TerminalMagicAction::Reconfigure(reconfigure_args) => update(
+            &global_config,
+            &git_repo,
+            &reconfigure_args.input,
+            true,
+            false,
+            true,
+        ),
+        TerminalMagicAction::Render(render_args) => {
+            let (script, outputs) = render_module(&global_config, &git_repo, &render_args.input);
+            let secrets =
+                installed_secret_values(&global_config.home.join(&render_args.input));
+            print!("{}", mask(&script, &secrets));
+            for (destination, output) in outputs {
+                println!("{}", format!("==> {} <==", destination.to_string_lossy()).bold());
+                print!("{}", mask(&output, &secrets));
+            }
+            std::process::exit(0);
+        }
+        TerminalMagicAction::Diff(diff_args) => {
+            diff_module(&global_config, &git_repo, &diff_args.input);
+            std::process::exit(0);
+        }
+        TerminalMagicAction::Remove(remove_args) => {
+            remove(&global_config, &remove_args.input);
+            if global_config.disabled_modules.contains(&remove_args.input) {
+                global_config
+                    .disabled_modules
+                    .retain(|module| *module != remove_args.input);
+                if !global_config.dry_run {
+                    global_config.save().expect("Could not save global config");
+                }
             }
         }
-        TerminalMagicAction::Remove(remove_args) => remove(&global_config, &remove_args.input),
+        TerminalMagicAction::Enable(enable_args) => {
+            set_enabled(&mut global_config, &enable_args.input, true)
+        }
+        TerminalMagicAction::Disable(disable_args) => {
+            set_enabled(&mut global_config, &disable_args.input, false)
+        }
+        TerminalMagicAction::Profile(profile_args) => {
+            profile(&global_config, &profile_args.shell);
+            std::process::exit(0);
+        }
+        TerminalMagicAction::Completions(_)
+        | TerminalMagicAction::Modules(_)
+        | TerminalMagicAction::New(_)
+        | TerminalMagicAction::Validate(_) => {}
     }
 
     if update_source_file(&global_config).is_err() {
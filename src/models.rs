@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use colored::Colorize;
 use dirs::home_dir;
@@ -28,24 +28,197 @@ pub struct PluginInfo {
     pub placeholders: Option<IndexMap<String, EntryType>>,
     // interpreter: Option<Interpreter>,
     pub supporting_files: Option<IndexMap<String, FileSystemEntry>>,
+    pub derived: Option<IndexMap<String, Derived>>,
+    pub completions: Option<Completions>,
+    pub hooks: Option<Hooks>,
 }
 
+/// Completion files shipped with the module, by shell. The paths are relative to the module, the
+/// file name is the name the completion is installed as.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Default)]
+pub struct Completions {
+    pub zsh: Option<String>,
+    pub bash: Option<String>,
+    pub fish: Option<String>,
+}
+
+impl Completions {
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, &String)> {
+        vec![("zsh", &self.zsh), ("bash", &self.bash), ("fish", &self.fish)]
+            .into_iter()
+            .filter_map(|(shell, path)| path.as_ref().map(|path| (shell, path)))
+    }
+}
+
+/// Scripts of the module, rendered with the placeholders, which run around install, update and
+/// remove. The paths are relative to the module.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Default)]
+pub struct Hooks {
+    pub pre_install: Option<String>,
+    pub post_install: Option<String>,
+    pub pre_update: Option<String>,
+    pub post_update: Option<String>,
+    pub pre_remove: Option<String>,
+}
+
+impl Hooks {
+    pub fn get(&self, hook: &str) -> Option<&String> {
+        match hook {
+            "pre_install" => self.pre_install.as_ref(),
+            "post_install" => self.post_install.as_ref(),
+            "pre_update" => self.pre_update.as_ref(),
+            "post_update" => self.post_update.as_ref(),
+            "pre_remove" => self.pre_remove.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Value computed from the placeholders, declared in the `[derived]` table of the config.
+/// Without a declaration every array placeholder `key` gets a `key_shortNames` value, joining
+/// the `shortName` fields with spaces.
 #[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 #[serde(untagged)]
+pub enum Derived {
+    /// Joins `field` (or the element itself) of every element of the array placeholder `source`.
+    Join {
+        source: String,
+        field: Option<String>,
+        #[serde(default = "default_separator")]
+        separator: String,
+        #[serde(default)]
+        quote: Quote,
+    },
+    /// Mustache template rendered with the placeholders, e.g. `"{{user}}@{{host}}"`.
+    Expression { expression: String },
+}
+
+fn default_separator() -> String {
+    String::from(" ")
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Quote {
+    #[default]
+    None,
+    Single,
+    Double,
+}
+
+impl Quote {
+    /// Quotes `value` so the shell reads it as a single word.
+    pub fn apply(&self, value: &str) -> String {
+        match self {
+            Quote::None => value.to_string(),
+            Quote::Single => format!("'{}'", value.replace('\'', "'\\''")),
+            Quote::Double => {
+                let mut quoted = String::from("\"");
+                for c in value.chars() {
+                    if matches!(c, '"' | '\\' | '$' | '`') {
+                        quoted.push('\\');
+                    }
+                    quoted.push(c);
+                }
+                quoted.push('"');
+                quoted
+            }
+        }
+    }
+}
+
+/// A file is tried first, an entry with `files` or `glob` is a directory.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+#[serde(untagged, deny_unknown_fields)]
 pub enum FileSystemEntry {
-    Directory {
+    File {
         version: String,
         path: String,
         destination: Option<String>,
-        files: IndexMap<String, FileSystemEntry>,
+        /// Render the file with the placeholders instead of copying it.
+        #[serde(default)]
+        template: bool,
+        /// Permissions of the installed file, e.g. `0o755` or `"0755"`.
+        #[serde(default, deserialize_with = "deserialize_mode")]
+        mode: Option<u32>,
+        /// Symlink to the file in the git repo instead of copying it.
+        #[serde(default)]
+        link: bool,
     },
-    File {
+    Directory {
         version: String,
         path: String,
         destination: Option<String>,
+        #[serde(default)]
+        files: IndexMap<String, FileSystemEntry>,
+        /// Also installs every file below `path` matching the pattern, e.g. `"**/*.sh"`.
+        glob: Option<String>,
+        /// Permissions of the files matched by `glob`.
+        #[serde(default, deserialize_with = "deserialize_mode")]
+        mode: Option<u32>,
+        /// Symlink to the directory in the git repo instead of creating it.
+        #[serde(default)]
+        link: bool,
     },
 }
 
+/// Reads a mode given as a number, e.g. `0o755`, or as an octal string, e.g. `"0755"`.
+fn deserialize_mode<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum RawMode {
+        Number(u32),
+        Octal(String),
+    }
+    let mode = match RawMode::deserialize(deserializer)? {
+        RawMode::Number(mode) => mode,
+        RawMode::Octal(mode) => {
+            let digits = mode.strip_prefix("0o").unwrap_or(&mode);
+            u32::from_str_radix(digits, 8).map_err(|_| {
+                serde::de::Error::custom(format!("mode `{}` is not an octal number", mode))
+            })?
+        }
+    };
+    if mode > 0o7777 {
+        return Err(serde::de::Error::custom(format!(
+            "mode {:o} is not a permission, write it in octal, e.g. 0o755",
+            mode
+        )));
+    }
+    Ok(Some(mode))
+}
+
+impl FileSystemEntry {
+    /// Where the entry ends up, relative to `cwd` unless an explicit destination is given.
+    pub fn destination(&self, cwd: &Path) -> PathBuf {
+        let (path, destination) = match self {
+            FileSystemEntry::Directory {
+                path, destination, ..
+            } => (path, destination),
+            FileSystemEntry::File {
+                path, destination, ..
+            } => (path, destination),
+        };
+        if let Some(destination) = destination {
+            destination
+                .to_owned()
+                .parse()
+                .expect("Could not parse path")
+        } else {
+            cwd.join(path)
+        }
+    }
+}
+
+/// A supporting file as it was installed, recorded to detect changes in the repo and local edits.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct InstalledFile {
+    pub version: String,
+    pub hash: String,
+}
+
 // #[derive(Deserialize,Serialize,Debug,PartialEq)]
 // struct Interpreter {
 //     name : String,
@@ -59,37 +232,338 @@ pub struct Package {
     pub help: Option<String>,
     pub internal_dependencies: Option<Vec<String>>,
     pub external_dependencies: Option<Vec<String>>,
+    /// Modules which should be sourced before this one, if they are installed.
+    pub load_after: Option<Vec<String>>,
+    /// Modules which should be sourced after this one, if they are installed.
+    pub load_before: Option<Vec<String>>,
+    /// Only source the script when one of its `functions` is called for the first time.
+    #[serde(default)]
+    pub lazy: bool,
+    /// The functions the script defines, the entry points of a lazy module.
+    pub functions: Option<Vec<String>>,
+    /// Insert `{{name}}` as it is, like `{{& name}}`, instead of HTML escaping it.
+    #[serde(default)]
+    pub unescaped: bool,
+    #[serde(deserialize_with = "deserialize_plugin_type")]
     pub plugin_type: PluginType,
 }
 
+/// A table with unknown keys matches no variant, so a misspelled `script` or `binary` table
+/// isn't read as a rust package without a source.
 #[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
-#[serde(untagged)]
+#[serde(untagged, deny_unknown_fields)]
 pub enum PluginType {
     Shell(String),
-    Script(String),
+    /// The rendered template is installed as the executable `script` into the `bin` directory of
+    /// terminal-magic, instead of being sourced.
+    Script {
+        script: String,
+        /// Used for the shebang if the template has none, `sh` by default.
+        interpreter: Option<String>,
+    },
+    /// A prebuilt executable or an archive containing it, installed as `binary` into the `bin`
+    /// directory of terminal-magic.
+    Prebuilt {
+        binary: String,
+        /// The sources by target triple, `*` matches every platform.
+        platforms: IndexMap<String, PrebuiltSource>,
+    },
     RustPackage {
         path: Option<String>,
         git: Option<String>,
         tag: Option<String>,
+        branch: Option<String>,
+        rev: Option<String>,
+        features: Option<Vec<String>>,
+        /// Only install these binaries of the package.
+        bins: Option<Vec<String>>,
+        /// Build with the `Cargo.lock` of the package.
+        #[serde(default)]
+        locked: bool,
     },
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+fn deserialize_plugin_type<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<PluginType, D::Error> {
+    let plugin_type = PluginType::deserialize(deserializer)?;
+    if let PluginType::RustPackage {
+        path: None,
+        git: None,
+        ..
+    } = plugin_type
+    {
+        return Err(serde::de::Error::custom(
+            "a rust package needs a `path` or `git` source",
+        ));
+    }
+    Ok(plugin_type)
+}
+
+impl PluginType {
+    /// The executables the module installs into the `bin` directory of terminal-magic.
+    pub fn binaries(&self) -> Vec<String> {
+        match self {
+            PluginType::Script { script, .. } => vec![script.to_owned()],
+            PluginType::Prebuilt { binary, .. } => vec![binary.to_owned()],
+            PluginType::RustPackage {
+                bins: Some(bins), ..
+            } => bins.clone(),
+            _ => vec![],
+        }
+    }
+
+    /// Whether the `script.sh` is sourced by the `env` file.
+    pub fn is_sourced(&self) -> bool {
+        !matches!(self, PluginType::Script { .. })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct PrebuiltSource {
+    /// `https://` or `file://` url to download from.
+    pub url: Option<String>,
+    /// Path relative to the module.
+    pub path: Option<String>,
+    pub sha256: String,
+    /// Path of the executable inside a `.tar.gz` or `.zip` archive, by default the entry named
+    /// like the binary.
+    pub file: Option<String>,
+}
+
+/// A placeholder in `config.toml` or its value in `data.toml`. A table is a `Typed` placeholder
+/// if its `type` is one of the placeholder types, otherwise it is an `Object`.
+#[derive(Serialize, Clone, Debug, PartialEq)]
 #[serde(untagged)]
 pub enum EntryType {
     Value(String),
+    Bool(bool),
+    Int(i64),
+    Typed(Placeholder),
     Object(IndexMap<String, EntryType>),
     Array(Vec<EntryType>),
 }
 
+/// The shape of an `EntryType` before tables are told apart. Nested values are kept as TOML, so
+/// their errors aren't swallowed by the untagged enum.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawEntryType {
+    Value(String),
+    Bool(bool),
+    Int(i64),
+    Table(IndexMap<String, toml::Value>),
+    Array(Vec<toml::Value>),
+}
+
+impl<'de> Deserialize<'de> for EntryType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let nested = |value: toml::Value| EntryType::deserialize(value).map_err(D::Error::custom);
+        Ok(match RawEntryType::deserialize(deserializer)? {
+            RawEntryType::Value(val) => EntryType::Value(val),
+            RawEntryType::Bool(val) => EntryType::Bool(val),
+            RawEntryType::Int(val) => EntryType::Int(val),
+            RawEntryType::Table(table) => {
+                let is_typed = table.get("type").is_some_and(|kind| {
+                    PlaceholderKind::deserialize(kind.clone()).is_ok()
+                });
+                if is_typed {
+                    let table: toml::value::Table = table.into_iter().collect();
+                    let placeholder = Placeholder::deserialize(toml::Value::Table(table))
+                        .map_err(D::Error::custom)?;
+                    if placeholder.kind == PlaceholderKind::Enum
+                        && placeholder.choices.as_ref().map_or(true, Vec::is_empty)
+                    {
+                        return Err(D::Error::custom("an enum placeholder needs `choices`"));
+                    }
+                    EntryType::Typed(placeholder)
+                } else {
+                    EntryType::Object(
+                        table
+                            .into_iter()
+                            .map(|(key, value)| Ok((key, nested(value)?)))
+                            .collect::<Result<_, D::Error>>()?,
+                    )
+                }
+            }
+            RawEntryType::Array(array) => EntryType::Array(
+                array
+                    .into_iter()
+                    .map(nested)
+                    .collect::<Result<_, D::Error>>()?,
+            ),
+        })
+    }
+}
+
+impl EntryType {
+    /// Whether the placeholder should be asked for, given the answers to the placeholders
+    /// declared before it.
+    pub fn is_active(&self, answers: &IndexMap<String, EntryType>) -> bool {
+        match self {
+            EntryType::Typed(Placeholder {
+                when: Some(condition),
+                ..
+            }) => condition_holds(condition, answers),
+            _ => true,
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        match self {
+            EntryType::Value(val) => !val.is_empty() && val != "false",
+            EntryType::Bool(val) => *val,
+            EntryType::Int(val) => *val != 0,
+            EntryType::Array(array) => !array.is_empty(),
+            EntryType::Object(_) | EntryType::Typed(_) => true,
+        }
+    }
+}
+
+pub fn condition_holds(condition: &str, answers: &IndexMap<String, EntryType>) -> bool {
+    let condition = condition.trim();
+    for (operator, negate) in [("!=", true), ("==", false)] {
+        if let Some((key, value)) = condition.split_once(operator) {
+            let value = value.trim().trim_matches(|c| c == '\'' || c == '"');
+            let equal = answers
+                .get(key.trim())
+                .is_some_and(|answer| answer.to_string() == value);
+            return equal != negate;
+        }
+    }
+    if let Some(key) = condition.strip_prefix('!') {
+        return !answers.get(key.trim()).is_some_and(EntryType::is_truthy);
+    }
+    answers.get(condition).is_some_and(EntryType::is_truthy)
+}
+
 impl std::fmt::Display for EntryType {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if let EntryType::Value(val) = self {
-            return f.write_str(val);
+        match self {
+            EntryType::Value(val) => f.write_str(val),
+            EntryType::Bool(val) => write!(f, "{}", val),
+            EntryType::Int(val) => write!(f, "{}", val),
+            _ => f.write_str(""),
+        }
+    }
+}
+
+/// Placeholder with a schema, e.g. `port = { type = "int", default = 5432 }`. The plain
+/// `key = "default"` form is still an `EntryType::Value`.
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Placeholder {
+    #[serde(rename = "type")]
+    pub kind: PlaceholderKind,
+    pub description: Option<String>,
+    pub default: Option<Box<EntryType>>,
+    pub choices: Option<Vec<String>>,
+    pub regex: Option<String>,
+    #[serde(default = "default_required")]
+    pub required: bool,
+    /// Only ask for this placeholder if the condition holds for the placeholders before it,
+    /// e.g. `use_tunnel`, `!use_tunnel` or `engine == postgres`.
+    pub when: Option<String>,
+    /// How the value is quoted when it is inserted into the template.
+    #[serde(default)]
+    pub quote: Quote,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaceholderKind {
+    String,
+    Bool,
+    Int,
+    Path,
+    Enum,
+    Secret,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+impl Placeholder {
+    pub fn new(kind: PlaceholderKind, default: EntryType) -> Self {
+        Self {
+            kind,
+            description: None,
+            default: Some(Box::new(default)),
+            choices: None,
+            regex: None,
+            required: true,
+            when: None,
+            quote: Quote::None,
+        }
+    }
+
+    /// Schema for a scalar placeholder. A plain string keeps the old behaviour: it is
+    /// tilde-expanded and may be left empty.
+    pub fn from_entry(entry: &EntryType) -> Self {
+        match entry {
+            EntryType::Typed(placeholder) => placeholder.clone(),
+            EntryType::Bool(_) => Self::new(PlaceholderKind::Bool, entry.clone()),
+            EntryType::Int(_) => Self::new(PlaceholderKind::Int, entry.clone()),
+            EntryType::Value(_) => Self {
+                required: false,
+                ..Self::new(PlaceholderKind::Path, entry.clone())
+            },
+            EntryType::Object(_) | EntryType::Array(_) => {
+                unreachable!("objects and arrays are not scalar placeholders")
+            }
+        }
+    }
+
+    /// Converts the user input into the value stored in `data.toml`, or explains why the input
+    /// is not valid for this placeholder.
+    pub fn parse(&self, input: &str) -> Result<EntryType, String> {
+        let input = input.trim();
+        if input.is_empty() {
+            if self.required {
+                return Err(String::from("A value is required"));
+            }
+            return Ok(EntryType::Value(String::new()));
+        }
+        if let Some(regex) = &self.regex {
+            let regex = regex::Regex::new(&format!("^(?:{})$", regex))
+                .map_err(|e| format!("Invalid regex in config: {}", e))?;
+            if !regex.is_match(input) {
+                return Err(format!("Value must match {}", regex.as_str()));
+            }
+        }
+        match self.kind {
+            PlaceholderKind::String | PlaceholderKind::Secret => {
+                Ok(EntryType::Value(input.to_string()))
+            }
+            PlaceholderKind::Path => Ok(EntryType::Value(shellexpand::tilde(input).to_string())),
+            PlaceholderKind::Int => input
+                .parse()
+                .map(EntryType::Int)
+                .map_err(|_| format!("{} is not a number", input)),
+            PlaceholderKind::Bool => match input.to_lowercase().as_str() {
+                "true" | "yes" | "y" => Ok(EntryType::Bool(true)),
+                "false" | "no" | "n" => Ok(EntryType::Bool(false)),
+                _ => Err(format!("{} is not a boolean", input)),
+            },
+            PlaceholderKind::Enum => {
+                let choices = self.choices.as_deref().unwrap_or_default();
+                if choices.iter().any(|choice| choice == input) {
+                    Ok(EntryType::Value(input.to_string()))
+                } else {
+                    Err(format!("Value must be one of {}", choices.join(", ")))
+                }
+            }
         }
-        f.write_str("")
     }
 }
+/// Serializes `value` as TOML. Going through `toml::Value` emits plain values before tables, so
+/// placeholders can be declared in any order.
+pub fn to_toml_string<T: Serialize>(value: &T) -> Result<String, toml::ser::Error> {
+    toml::to_string(&toml::Value::try_from(value)?)
+}
+
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct GlobalConfig {
     pub config_path: PathBuf,
@@ -101,6 +575,18 @@ pub struct GlobalConfig {
     pub key_needs_pw: bool,
     #[serde(default = "default_home")]
     pub home: PathBuf,
+    /// Installed modules which are left out of the `env` file.
+    #[serde(default)]
+    pub disabled_modules: Vec<String>,
+    #[serde(skip)]
+    pub dry_run: bool,
+    /// Abort `install` and `update` if a template uses a variable without a value.
+    #[serde(skip, default = "default_strict")]
+    pub strict: bool,
+}
+
+fn default_strict() -> bool {
+    true
 }
 
 fn default_branch() -> String {
@@ -133,7 +619,10 @@ impl GlobalConfig {
                 ssh_key: None,
                 key_needs_pw: false,
                 home: config_dir,
-                git_main_branch: String::from("main")
+                git_main_branch: String::from("main"),
+                disabled_modules: vec![],
+                dry_run: false,
+                strict: true,
             };
             if res.save().is_err() {
                 eprintln!("{}", "Could not write config".red());
@@ -152,3 +641,156 @@ impl Default for GlobalConfig {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn placeholders(source: &str) -> Result<IndexMap<String, EntryType>, toml::de::Error> {
+        toml::from_str(source)
+    }
+
+    #[test]
+    fn tables_with_a_placeholder_type_are_typed() {
+        let parsed = placeholders(
+            r#"
+            port = { type = "int", default = 5432 }
+            conn = { type = "tcp", host = "localhost" }
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(&parsed["port"], EntryType::Typed(p) if p.kind == PlaceholderKind::Int));
+        assert!(matches!(&parsed["conn"], EntryType::Object(obj) if obj.len() == 2));
+    }
+
+    #[test]
+    fn rejects_enums_without_choices_and_unknown_keys() {
+        for source in [
+            r#"engine = { type = "enum" }"#,
+            r#"engine = { type = "enum", choices = [] }"#,
+            r#"[conn]
+               mode = { type = "enum" }"#,
+            r#"port = { type = "int", host = "localhost" }"#,
+        ] {
+            assert!(placeholders(source).is_err(), "{}", source);
+        }
+        assert!(placeholders(r#"engine = { type = "enum", choices = ["pg"] }"#).is_ok());
+    }
+
+    #[test]
+    fn modes_are_octal_numbers_or_strings() {
+        let mode = |entry: &str| match toml::from_str::<FileSystemEntry>(entry) {
+            Ok(FileSystemEntry::File { mode, .. }) => Ok(mode),
+            Ok(entry) => panic!("not a file: {:?}", entry),
+            Err(_) => Err(()),
+        };
+        let file = "version = \"1\"\npath = \"helper.sh\"\n";
+        assert_eq!(mode(&format!("{}mode = 0o755", file)), Ok(Some(0o755)));
+        assert_eq!(mode(&format!("{}mode = \"0755\"", file)), Ok(Some(0o755)));
+        assert_eq!(mode(&format!("{}mode = \"0o644\"", file)), Ok(Some(0o644)));
+        assert_eq!(mode(file), Ok(None));
+        assert_eq!(mode(&format!("{}mode = \"0789\"", file)), Err(()));
+        assert_eq!(mode(&format!("{}mode = 0o17777", file)), Err(()));
+    }
+
+    fn placeholder(source: &str) -> Placeholder {
+        match placeholders(&format!("key = {}", source)).unwrap().remove("key") {
+            Some(EntryType::Typed(placeholder)) => placeholder,
+            entry => panic!("not a typed placeholder: {:?}", entry),
+        }
+    }
+
+    #[test]
+    fn parses_input_by_placeholder_type() {
+        let int = placeholder(r#"{ type = "int" }"#);
+        assert_eq!(int.parse(" 42 "), Ok(EntryType::Int(42)));
+        assert!(int.parse("forty").is_err());
+        assert!(int.parse("").is_err());
+
+        let bool = placeholder(r#"{ type = "bool", required = false }"#);
+        assert_eq!(bool.parse("Yes"), Ok(EntryType::Bool(true)));
+        assert_eq!(bool.parse("n"), Ok(EntryType::Bool(false)));
+        assert!(bool.parse("maybe").is_err());
+        assert_eq!(bool.parse(""), Ok(EntryType::Value(String::new())));
+
+        let engine = placeholder(r#"{ type = "enum", choices = ["mysql", "postgres"] }"#);
+        assert_eq!(engine.parse("postgres"), Ok(EntryType::Value("postgres".to_string())));
+        assert_eq!(
+            engine.parse("sqlite"),
+            Err("Value must be one of mysql, postgres".to_string())
+        );
+
+        let container = placeholder(r#"{ type = "string", regex = "[a-z]+" }"#);
+        assert_eq!(container.parse("web"), Ok(EntryType::Value("web".to_string())));
+        // the regex has to match the whole value
+        assert!(container.parse("web-1").is_err());
+
+        let path = placeholder(r#"{ type = "path" }"#);
+        assert!(!path.parse("~/bin").unwrap().to_string().starts_with('~'));
+    }
+
+    #[test]
+    fn evaluates_when_conditions() {
+        let answers = placeholders(
+            r#"
+            use_tunnel = true
+            port = 0
+            engine = "postgres"
+            "#,
+        )
+        .unwrap();
+        for (condition, holds) in [
+            ("use_tunnel", true),
+            ("!use_tunnel", false),
+            ("port", false),
+            ("! port", true),
+            ("missing", false),
+            ("!missing", true),
+            ("engine == postgres", true),
+            ("engine == 'postgres'", true),
+            ("engine==\"mysql\"", false),
+            ("engine != mysql", true),
+            ("missing != mysql", true),
+            ("missing == mysql", false),
+        ] {
+            assert_eq!(condition_holds(condition, &answers), holds, "{}", condition);
+        }
+    }
+
+    #[test]
+    fn quotes_values_as_one_shell_word() {
+        let value = r#"it's "$HOME" `pwd` \n"#;
+        assert_eq!(Quote::None.apply(value), value);
+        assert_eq!(Quote::Single.apply(value), r#"'it'\''s "$HOME" `pwd` \n'"#);
+        assert_eq!(Quote::Double.apply(value), r#""it's \"\$HOME\" \`pwd\` \\n""#);
+    }
+
+    #[test]
+    fn rejects_malformed_plugin_types() {
+        let plugin_type = |source: &str| {
+            toml::from_str::<PluginInfo>(&format!(
+                "[plugin_info]\nauthor = \"a\"\nversion = \"1.0.0\"\nplugin_type = {}",
+                source
+            ))
+            .map(|config| config.plugin_info.plugin_type)
+        };
+        for source in [
+            r#"{ scirpt = "tool" }"#,
+            r#"{ script = "tool", interpreter = "bash", mode = 1 }"#,
+            r#"{ binary = "tool" }"#,
+            r#"{ path = "crate", tag = "v1", colour = true }"#,
+            r#"{ tag = "v1" }"#,
+            "{}",
+        ] {
+            assert!(plugin_type(source).is_err(), "{}", source);
+        }
+        assert!(matches!(
+            plugin_type(r#"{ git = "https://example.com/tool", tag = "v1" }"#),
+            Ok(PluginType::RustPackage { .. })
+        ));
+        assert!(matches!(
+            plugin_type(r#"{ script = "tool" }"#),
+            Ok(PluginType::Script { .. })
+        ));
+    }
+}
@@ -1,10 +1,9 @@
-use std::{path::{Path, PathBuf}, process::Command};
+use std::path::{Path, PathBuf};
 
 use colored::Colorize;
 use indexmap::IndexMap;
-use mustache::MapBuilder;
 
-use crate::{modules::{update::update, read_config}, models::{GlobalConfig, PluginType, FileSystemEntry, PluginInfo}, prompts::read, template::{add_files_as_vars, render}};
+use crate::{binaries::{install_binary, remove_binaries}, completions::install_completions, filesystem, hooks::{run_module_hook, save_pre_remove_hook}, secrets::{extract_secrets, find_secrets, SECRETS_FILE}, modules::{update::update, install_conflicts, install_package, print_file_diff, read_config}, models::{to_toml_string, GlobalConfig, InstalledFile, PluginType, FileSystemEntry, PluginInfo}, prompts::{boolean_prompt, read}, template::{add_files_as_vars, add_placeholders_as_vars, check_strict, compile_template, lint_quoting, map_builder, read_template_sources, render, render_outputs, PARTIALS_FILE}};
 
 // Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
 // 
@@ -15,7 +14,7 @@ use crate::{modules::{update::update, read_config}, models::{GlobalConfig, Plugi
 pub fn install(global_config: &GlobalConfig, git_repo: &str, plugin_name: &str) {
     let home_path = global_config.home.join(plugin_name);
     if home_path.exists() {
-        update(global_config, git_repo, plugin_name, false, true);
+        update(global_config, git_repo, plugin_name, false, true, false);
         return;
     }
     let path_to_module = Path::new(git_repo).join(plugin_name);
@@ -26,10 +25,18 @@ pub fn install(global_config: &GlobalConfig, git_repo: &str, plugin_name: &str)
         );
         std::process::exit(1);
     }
-    let mustache = mustache::compile_path(path_to_module.join("template.sh"))
-        .expect("Could not parse mustache template");
-
-    let mut toml = read_config(&path_to_module.join("config.toml")).expect("Cannot find TOML");
+    let config = read_config(&path_to_module.join("config.toml")).expect("Cannot find TOML");
+    let unescaped = config.plugin_info.unescaped;
+    let mustache = compile_template(git_repo, plugin_name, "template.sh", unescaped);
+    // the placeholders are replaced by the answers, `config` keeps the schema
+    let mut toml = config.clone();
+    let conflicts = install_conflicts(global_config, plugin_name, &toml);
+    if !conflicts.is_empty() {
+        for conflict in conflicts {
+            eprintln!("{}", conflict.red());
+        }
+        std::process::exit(1);
+    }
     if let Some(internal_deps) = toml.plugin_info.internal_dependencies.as_mut() {
         for dep in internal_deps {
             install(global_config, git_repo, dep);
@@ -44,159 +51,437 @@ pub fn install(global_config: &GlobalConfig, git_repo: &str, plugin_name: &str)
             );
         }
     }
-    let mut mustache_map_builder = MapBuilder::new();
-    if let Some(placeholders) = toml.placeholders.as_mut() {
-        for placeholder in placeholders.iter_mut() {
-            println!("Read {}", placeholder.0);
-            let (new_mustache_map_builder, object) =
-                read(placeholder.0, placeholder.1, mustache_map_builder);
-            mustache_map_builder = new_mustache_map_builder;
-
-            mustache_map_builder = mustache_map_builder
-                .insert(placeholder.0, &object)
-                .expect("Could not parse object");
-            *placeholder.1 = object;
+    let mut mustache_map_builder = map_builder();
+    // the supporting files aren't copied yet when the pre_install hook runs
+    let mut hook_map_builder = map_builder();
+    let mut secrets = vec![];
+    if let Some(placeholders) = toml.placeholders.clone() {
+        let mut answers = IndexMap::new();
+        for (key, entry) in placeholders.iter() {
+            if !entry.is_active(&answers) {
+                continue;
+            }
+            println!("Read {}", key);
+            answers.insert(key.to_owned(), read(key, entry));
         }
+        let add_placeholders = |builder| match add_placeholders_as_vars(&answers, &toml, builder) {
+            Ok(builder) => builder,
+            Err(err) => {
+                eprintln!("{}", err.red());
+                std::process::exit(1);
+            }
+        };
+        mustache_map_builder = add_placeholders(mustache_map_builder);
+        hook_map_builder = add_placeholders(hook_map_builder);
+        lint_quoting(git_repo, plugin_name, "template.sh", &toml, &answers);
+        secrets = find_secrets(Some(&placeholders), Some(&answers));
+        toml.placeholders = Some(answers);
     }
-    let home_path = global_config.home.join(plugin_name);
-    if std::fs::create_dir_all(&home_path).is_ok() {
+    if let Some(files) = &toml.supporting_files {
+        mustache_map_builder = add_files_as_vars(files, mustache_map_builder, &home_path);
+    }
+    let mustache_map = mustache_map_builder.build();
+    // nothing is touched before the templates are known to render
+    if !check_strict(global_config, git_repo, plugin_name, &config, &mustache_map) {
+        std::process::exit(1);
+    }
+    if !run_module_hook(
+        global_config,
+        git_repo,
+        plugin_name,
+        &toml,
+        "pre_install",
+        &hook_map_builder.build(),
+        &secrets,
+    ) {
+        std::process::exit(1);
+    }
+    if filesystem::create_dir_all(global_config, &home_path).is_ok() && !global_config.dry_run {
         println!("Created Plugin directory");
     }
     println!("Copying supporting files");
     if let Some(files) = &toml.supporting_files {
-        mustache_map_builder = add_files_as_vars(
-            files,
-            mustache_map_builder,
-            &home_path,
-            &path_to_module,
-            &home_path,
-            true,
-        );
+        write_supporting_files(global_config, files, &path_to_module, &home_path);
     }
-    if let PluginType::RustPackage { path, git, tag } = &toml.plugin_info.plugin_type {
-        let mut install_command = Command::new("cargo");
-        if let Some(git) = git {
-            install_command
-                .env("CARGO_NET_GIT_FETCH_WITH_CLI", "true")
-                .arg("install")
-                .arg("--git")
-                .arg(git);
-            if let Some(tag) = tag {
-                install_command.arg("--tag").arg(tag);
-            }
-        } else if let Some(path) = path {
-            install_command
-                .arg("install")
-                .arg("--path")
-                .arg(&path_to_module.join(path));
-        } else {
-            panic!("either path or git should be set");
-        };
-        match install_command
-            .spawn()
-            .expect("Could not install module")
-            .wait_with_output()
-        {
-            Ok(_) => println!("Successfully installed rust-module"),
-            Err(err) => panic!("{:?}", err),
-        }
+    if let Err(err) = install_package(
+        global_config,
+        plugin_name,
+        &toml.plugin_info.plugin_type,
+        &path_to_module,
+    ) {
+        eprintln!("{}", err.red());
+        // don't leave a half installed module behind
+        let _ = filesystem::remove_dir_all(global_config, &home_path);
+        std::process::exit(1);
     }
 
-    let mustache_map = mustache_map_builder.build();
-    let script = render(mustache, mustache_map);
-    write_file(global_config, toml, script, plugin_name, &path_to_module);
+    let script = render(mustache, &mustache_map);
+    let outputs = match &toml.supporting_files {
+        Some(files) => {
+            render_outputs(git_repo, plugin_name, files, &home_path, &mustache_map, unescaped)
+        }
+        None => vec![],
+    };
+    write_file(global_config, git_repo, toml.clone(), script, outputs, plugin_name);
+    save_pre_remove_hook(global_config, git_repo, plugin_name, &toml, &mustache_map);
+    if !run_module_hook(
+        global_config,
+        git_repo,
+        plugin_name,
+        &toml,
+        "post_install",
+        &mustache_map,
+        &secrets,
+    ) {
+        std::process::exit(1);
+    }
 }
 
 
+pub static MANIFEST_FILE: &str = "files.toml";
+
+/// The supporting files as they were installed, by destination.
+pub fn read_manifest(home_path: &Path) -> IndexMap<String, InstalledFile> {
+    std::fs::read_to_string(home_path.join(MANIFEST_FILE))
+        .ok()
+        .and_then(|manifest| toml::from_str(&manifest).ok())
+        .unwrap_or_default()
+}
+
+/// Copies the supporting files whose version or content changed in the repo. A file which
+/// exists and differs from the new version is only overwritten after showing the diff and asking.
 pub fn write_supporting_files(
+    global_config: &GlobalConfig,
+    files: &IndexMap<String, FileSystemEntry>,
+    path_to_module: &Path,
+    home_path: &Path,
+) {
+    let mut manifest = read_manifest(home_path);
+    write_entries(global_config, files, path_to_module, home_path, &mut manifest);
+    if filesystem::write(
+        global_config,
+        &home_path.join(MANIFEST_FILE),
+        to_toml_string(&manifest).expect("could not serialize supporting files"),
+    )
+    .is_err()
+    {
+        eprintln!("{}", "Could not write the list of supporting files".red());
+    }
+}
+
+fn write_entries(
+    global_config: &GlobalConfig,
     files: &IndexMap<String, FileSystemEntry>,
-    home: &Path,
     path_to_module: &Path,
     cwd: &Path,
+    manifest: &mut IndexMap<String, InstalledFile>,
 ) {
     for (_, file) in files {
+        let destination = file.destination(cwd);
         match file {
+            // rendered together with the script, see `render_outputs`
+            FileSystemEntry::File { template: true, .. } => {}
+            FileSystemEntry::File {
+                path, link: true, ..
+            }
+            | FileSystemEntry::Directory {
+                path, link: true, ..
+            } => {
+                manifest.shift_remove(destination.to_string_lossy().as_ref());
+                link_entry(global_config, &path_to_module.join(path), &destination);
+            }
             FileSystemEntry::File {
                 version,
                 path,
-                destination,
+                mode,
+                ..
             } => {
-                let destination = if let Some(destination) = destination {
-                    destination
-                        .to_owned()
-                        .parse()
-                        .expect("Could not parse path")
-                } else {
-                    cwd.join(path)
-                };
-                if std::fs::remove_file(&destination).is_ok() {
-                    println!(
-                        "{:?} existed, overwriting with new version: {}",
-                        destination, version
-                    );
-                }
                 let source_relative = path.parse::<PathBuf>().expect("Source path is invalid");
                 let source = path_to_module.join(source_relative);
-                if let Err(err) = std::fs::copy(&source, &destination) {
-                    panic!(
-                        "Could not copy file from source {:?} to {:?}\n{:?}",
-                        source, destination, err
-                    );
+                copy_entry(global_config, version, &source, &destination, manifest);
+                if let Some(mode) = mode {
+                    set_mode(global_config, &destination, *mode);
                 }
             }
             FileSystemEntry::Directory {
                 version,
                 path,
-                destination,
                 files,
+                glob,
+                mode,
+                ..
             } => {
-                let destination = if let Some(destination) = destination {
-                    destination
-                        .to_owned()
-                        .parse()
-                        .expect("Could not parse path")
-                } else {
-                    cwd.join(path)
-                };
-
-                if std::fs::create_dir_all(&destination).is_ok() {
+                if filesystem::create_dir_all(global_config, &destination).is_ok()
+                    && !global_config.dry_run
+                {
                     println!("Created {:?} [{}]", destination, version);
                 }
-                write_supporting_files(files, home, path_to_module, &destination);
+                write_entries(global_config, files, path_to_module, &destination, manifest);
+                let source_dir = path_to_module.join(path);
+                for relative in glob_files(&source_dir, glob.as_deref()) {
+                    let target = destination.join(&relative);
+                    if let Some(parent) = target.parent() {
+                        let _ = filesystem::create_dir_all(global_config, parent);
+                    }
+                    copy_entry(
+                        global_config,
+                        version,
+                        &source_dir.join(&relative),
+                        &target,
+                        manifest,
+                    );
+                    if let Some(mode) = mode {
+                        set_mode(global_config, &target, *mode);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The files below `dir` matching `pattern`, relative to `dir`. Prints an error and returns no
+/// files if the pattern is invalid.
+pub fn glob_files(dir: &Path, pattern: Option<&str>) -> Vec<PathBuf> {
+    let pattern = match pattern {
+        Some(pattern) => pattern,
+        None => return vec![],
+    };
+    if let Err(err) = glob::Pattern::new(pattern) {
+        eprintln!("{} `{}`: {}", "Invalid glob".red(), pattern, err);
+        return vec![];
+    }
+    let escaped_dir = glob::Pattern::escape(&dir.to_string_lossy());
+    glob::glob(&Path::new(&escaped_dir).join(pattern).to_string_lossy())
+        .map(|paths| {
+            paths
+                .filter_map(Result::ok)
+                .filter(|path| path.is_file())
+                .filter_map(|path| path.strip_prefix(dir).map(Path::to_path_buf).ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn copy_entry(
+    global_config: &GlobalConfig,
+    version: &str,
+    source: &Path,
+    destination: &Path,
+    manifest: &mut IndexMap<String, InstalledFile>,
+) {
+    let new = std::fs::read(source)
+        .unwrap_or_else(|err| panic!("Could not read supporting file {:?}\n{:?}", source, err));
+    let new_hash = filesystem::sha256(&new);
+    let key = destination.to_string_lossy().to_string();
+    let installed = manifest.get(&key);
+    // a destination the user deleted is copied again
+    if installed.is_some_and(|i| i.version == version && i.hash == new_hash)
+        && destination.exists()
+    {
+        return;
+    }
+    let current = std::fs::read(destination).ok();
+    let current_hash = current.as_deref().map(filesystem::sha256);
+    if let Some(current) = current.filter(|_| current_hash.as_ref() != Some(&new_hash)) {
+        println!(
+            "{:?} changed [{} -> {}]",
+            destination,
+            installed.map_or("unknown", |i| i.version.as_str()),
+            version
+        );
+        // without a record of the installed file, assume it was edited
+        let edited_locally = match installed {
+            Some(installed) => Some(&installed.hash) != current_hash.as_ref(),
+            None => true,
+        };
+        if edited_locally {
+            println!(
+                "{}",
+                "The file was modified locally, updating it discards the changes".red()
+            );
+        }
+        print_file_diff(
+            &destination.to_string_lossy(),
+            &source.to_string_lossy(),
+            &current,
+            &new,
+        );
+        if !boolean_prompt(&format!("Update {}?", destination.to_string_lossy())) {
+            return;
+        }
+    }
+    // a link left over from `link = true` would make the copy write into the repo
+    if destination.is_symlink() {
+        let _ = filesystem::remove_file(global_config, destination);
+    }
+    if let Err(err) = filesystem::copy(global_config, source, destination) {
+        panic!(
+            "Could not copy file from source {:?} to {:?}\n{:?}",
+            source, destination, err
+        );
+    }
+    manifest.insert(
+        key,
+        InstalledFile {
+            version: version.to_owned(),
+            hash: new_hash,
+        },
+    );
+}
+
+fn link_entry(global_config: &GlobalConfig, source: &Path, destination: &Path) {
+    let source = source.canonicalize().unwrap_or_else(|_| source.to_path_buf());
+    match std::fs::symlink_metadata(destination) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            if std::fs::read_link(destination).ok().as_deref() == Some(source.as_path()) {
+                return;
+            }
+            let _ = filesystem::remove_file(global_config, destination);
+        }
+        Ok(metadata) => {
+            if !boolean_prompt(&format!(
+                "Replace {} with a link to {}?",
+                destination.to_string_lossy(),
+                source.to_string_lossy()
+            )) {
+                return;
             }
+            let removed = if metadata.is_dir() {
+                filesystem::remove_dir_all(global_config, destination)
+            } else {
+                filesystem::remove_file(global_config, destination)
+            };
+            removed.expect("Could not remove the file to link");
         }
+        Err(_) => {}
+    }
+    if let Err(err) = filesystem::symlink(global_config, &source, destination) {
+        panic!(
+            "Could not link {:?} to {:?}\n{:?}",
+            destination, source, err
+        );
+    }
+}
+
+fn set_mode(global_config: &GlobalConfig, path: &Path, mode: u32) {
+    if let Err(err) = filesystem::set_mode(global_config, path, mode) {
+        eprintln!("{} {:?}: {:?}", "Could not set the mode of".red(), path, err);
     }
 }
 
 
-pub fn write_file(global_config: &GlobalConfig, toml: PluginInfo, script: String, plugin_name: &str, path_to_module: &Path) {
+pub fn write_file(global_config: &GlobalConfig, git_repo: &str, mut toml: PluginInfo, script: String, outputs: Vec<(PathBuf, String)>, plugin_name: &str) {
     let home_path = global_config.home.join(plugin_name);
-    if std::fs::create_dir_all(&home_path).is_ok() {
+    let path_to_module = Path::new(git_repo).join(plugin_name);
+    let config = read_config(&path_to_module.join("config.toml")).expect("Cannot find TOML");
+    let secrets = match (&config.placeholders, toml.placeholders.as_mut()) {
+        (Some(proto), Some(data)) => extract_secrets(proto, data),
+        _ => IndexMap::new(),
+    };
+    // the filesystem helpers print the plan in dry-run mode, don't claim it happened
+    let dry_run = global_config.dry_run;
+    if filesystem::create_dir_all(global_config, &home_path).is_ok() && !dry_run {
         println!("Created directory");
     }
-    if std::fs::remove_file(home_path.join("script.sh")).is_ok() {
+    if filesystem::remove_file(global_config, &home_path.join("script.sh")).is_ok() && !dry_run {
         println!("script.sh already existed");
     }
-    if std::fs::copy(
-        path_to_module.join("config.toml"),
-        home_path.join("config.toml"),
-    )
-    .is_ok()
-    {}
+    let _ = filesystem::copy(
+        global_config,
+        &path_to_module.join("config.toml"),
+        &home_path.join("config.toml"),
+    );
 
     // if let Some(files) = &toml.supporting_files {
     //     write_supporting_files(files, &home_path, path_to_module, &home_path);
     // }
 
-    if std::fs::write(home_path.join("script.sh"), script).is_ok() {
-        if std::fs::remove_file(home_path.join("data.toml")).is_ok() {
+    // the script must not reference secrets which were never stored
+    let secrets_written = if secrets.is_empty() {
+        let _ = filesystem::remove_file(global_config, &home_path.join(SECRETS_FILE));
+        Ok(())
+    } else {
+        filesystem::write_private(
+            global_config,
+            &home_path.join(SECRETS_FILE),
+            to_toml_string(&secrets).expect("could not serialize secrets"),
+        )
+    };
+    if secrets_written.is_err() {
+        eprintln!("{}", "Could not write secrets".red());
+        return;
+    }
+    let script_written = if secrets.is_empty() {
+        filesystem::write(global_config, &home_path.join("script.sh"), &script)
+    } else {
+        filesystem::write_private(global_config, &home_path.join("script.sh"), &script)
+    };
+    install_completions(global_config, plugin_name, config.completions.as_ref(), &path_to_module);
+    // the binaries of rust packages are tracked by `install_rust_package`
+    if !matches!(config.plugin_info.plugin_type, PluginType::RustPackage { .. }) {
+        let binaries = config.plugin_info.plugin_type.binaries();
+        remove_binaries(global_config, plugin_name, &binaries);
+    }
+    if let PluginType::Script {
+        script: name,
+        interpreter,
+    } = &config.plugin_info.plugin_type
+    {
+        let executable = if script.starts_with("#!") {
+            script.clone()
+        } else {
+            format!(
+                "#!/usr/bin/env {}\n{}",
+                interpreter.as_deref().unwrap_or("sh"),
+                script
+            )
+        };
+        let mode = if secrets.is_empty() { 0o755 } else { 0o700 };
+        if let Err(err) =
+            install_binary(global_config, plugin_name, name, executable.as_bytes(), mode)
+        {
+            eprintln!("{} {}: {:?}", "Could not install executable".red(), name, err);
+        }
+    }
+    // the outputs belong to the script, don't leave them behind without it
+    if script_written.is_ok() {
+        for (destination, output) in outputs {
+            if let Some(parent) = destination.parent() {
+                let _ = filesystem::create_dir_all(global_config, parent);
+            }
+            let written = if secrets.is_empty() {
+                filesystem::write(global_config, &destination, output)
+            } else {
+                filesystem::write_private(global_config, &destination, output)
+            };
+            if written.is_err() {
+                eprintln!("{} {:?}", "Could not write".red(), destination);
+            }
+        }
+        if filesystem::remove_file(global_config, &home_path.join("data.toml")).is_ok() && !dry_run
+        {
             println!("data.toml File existed");
         }
-        if std::fs::write(
-            home_path.join("data.toml"),
-            toml::to_vec(&toml).expect("could not serialize data"),
+        let sources = read_template_sources(git_repo, plugin_name, &config)
+            .expect("Could not read mustache template");
+        if sources.is_empty() {
+            let _ = filesystem::remove_file(global_config, &home_path.join(PARTIALS_FILE));
+        } else if filesystem::write(
+            global_config,
+            &home_path.join(PARTIALS_FILE),
+            to_toml_string(&sources).expect("could not serialize partials"),
+        )
+        .is_err()
+        {
+            eprintln!("{}", "Could not write partials".red());
+        }
+        if filesystem::write(
+            global_config,
+            &home_path.join("data.toml"),
+            to_toml_string(&toml).expect("could not serialize data"),
         )
         .is_ok()
+            && !dry_run
         {
             println!("Successfully wrote plugin {}!", plugin_name);
         }
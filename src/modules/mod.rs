@@ -1,9 +1,21 @@
-use std::path::Path;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::Path,
+};
 
 use colored::{ColoredString, Colorize};
+use indexmap::IndexMap;
 use semver::Version;
 
-use crate::models::{GlobalConfig, ModuleState, PluginInfo, UpdateReason};
+use crate::{
+    binaries::{binary_conflicts, remove_binaries, BIN_DIR},
+    completions::{completion_conflicts, read_registry, remove_completions, COMPLETION_DIR},
+    filesystem,
+    hooks::run_pre_remove_hook,
+    models::{GlobalConfig, ModuleState, PluginInfo, PluginType, UpdateReason},
+    secrets::{merge_secrets, read_secrets},
+    template::{read_installed_sources, read_template_sources},
+};
 
 // Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
 //
@@ -11,7 +23,13 @@ use crate::models::{GlobalConfig, ModuleState, PluginInfo, UpdateReason};
 // https://opensource.org/licenses/MIT
 
 pub mod install;
+pub mod new;
+pub mod prebuilt;
+pub mod profile;
+pub mod render;
+pub mod rust_package;
 pub mod update;
+pub mod validate;
 
 pub fn read_dir(global_config: &GlobalConfig, dir: &Path, base: &str) -> std::io::Result<()> {
     if dir.is_dir() {
@@ -38,7 +56,14 @@ pub fn read_dir(global_config: &GlobalConfig, dir: &Path, base: &str) -> std::io
                     let toml = read_config(&module_path.join("config.toml"))?;
                     let new_toml = read_config(&dir.join("config.toml")).unwrap();
                     version = toml.plugin_info.version.clone();
-                    installed = "(installed)";
+                    installed = if global_config
+                        .disabled_modules
+                        .contains(&module.to_string_lossy().to_string())
+                    {
+                        "(installed, disabled)"
+                    } else {
+                        "(installed)"
+                    };
                     if version != new_toml.plugin_info.version {
                         repo_version = format!(" ({}) ", new_toml.plugin_info.version);
                     }
@@ -58,6 +83,24 @@ pub fn read_dir(global_config: &GlobalConfig, dir: &Path, base: &str) -> std::io
     Ok(())
 }
 
+/// The names of all modules in the git repo below `dir`.
+pub fn get_list_of_available_modules(dir: &Path, base: &str) -> Vec<String> {
+    let mut modules = vec![];
+    for entry in std::fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        if path.to_string_lossy().contains(".git") {
+            continue;
+        }
+        if path.is_dir() {
+            modules.append(&mut get_list_of_available_modules(&path, base));
+        } else if path.ends_with("config.toml") {
+            modules.push(dir.strip_prefix(base).unwrap().to_string_lossy().to_string());
+        }
+    }
+    modules.sort();
+    modules
+}
+
 pub fn read_config(config_path: &Path) -> Result<PluginInfo, std::io::Error> {
     let toml_str = std::fs::read_to_string(config_path)?;
     if let Ok(pi) = toml::from_str(&toml_str) {
@@ -66,6 +109,16 @@ pub fn read_config(config_path: &Path) -> Result<PluginInfo, std::io::Error> {
     Err(std::io::Error::from(std::io::ErrorKind::InvalidData))
 }
 
+/// Reads the `data.toml` of an installed module, with the secrets merged back in.
+pub fn read_data(home_path: &Path) -> Result<PluginInfo, std::io::Error> {
+    let mut data = read_config(&home_path.join("data.toml"))?;
+    let secrets = read_secrets(home_path)?;
+    if !secrets.is_empty() {
+        merge_secrets(data.placeholders.get_or_insert_with(IndexMap::new), secrets);
+    }
+    Ok(data)
+}
+
 pub fn get_list_of_installed_modules(path: &Path, base: &str) -> std::io::Result<Vec<String>> {
     let mut out_result: Vec<String> = vec![];
     if path.is_dir() {
@@ -88,28 +141,173 @@ pub fn get_list_of_installed_modules(path: &Path, base: &str) -> std::io::Result
     }
     Ok(out_result)
 }
+/// Orders the installed modules so that every module is sourced after its internal dependencies
+/// and `load_after` modules, and before its `load_before` modules. Modules without a constraint
+/// between them are sorted by name, so the order does not depend on the filesystem.
+pub fn load_order(global_config: &GlobalConfig, modules: Vec<String>) -> Vec<String> {
+    let mut pending: BTreeMap<String, BTreeSet<String>> = modules
+        .iter()
+        .map(|module| (module.to_owned(), BTreeSet::new()))
+        .collect();
+    for module in &modules {
+        let package = match read_config(&global_config.home.join(module).join("config.toml")) {
+            Ok(config) => config.plugin_info,
+            Err(_) => continue,
+        };
+        let earlier = package
+            .internal_dependencies
+            .iter()
+            .flatten()
+            .chain(package.load_after.iter().flatten());
+        for earlier in earlier {
+            if earlier != module && pending.contains_key(earlier) {
+                pending.get_mut(module).unwrap().insert(earlier.to_owned());
+            }
+        }
+        for later in package.load_before.iter().flatten() {
+            if let Some(earlier) = pending.get_mut(later).filter(|_| later != module) {
+                earlier.insert(module.to_owned());
+            }
+        }
+    }
+    let mut ordered = vec![];
+    while !pending.is_empty() {
+        let next = match pending.iter().find(|(_, earlier)| earlier.is_empty()) {
+            Some((module, _)) => module.to_owned(),
+            None => {
+                eprintln!(
+                    "{} {}",
+                    "Cyclic load order between".red(),
+                    pending.keys().cloned().collect::<Vec<String>>().join(", ")
+                );
+                pending.keys().next().unwrap().to_owned()
+            }
+        };
+        pending.remove(&next);
+        for earlier in pending.values_mut() {
+            earlier.remove(&next);
+        }
+        ordered.push(next);
+    }
+    ordered
+}
+
+/// The lines of the `env` file loading a module, if it is sourced at all. A lazy module gets a
+/// wrapper for each of its functions, which removes the wrappers, sources the script and calls
+/// the real function.
+fn source_lines(global_config: &GlobalConfig, module: &str) -> Option<String> {
+    let script = global_config.home.join(module).join("script.sh");
+    let source = format!("source {}", script.to_string_lossy());
+    let package = match read_config(&global_config.home.join(module).join("config.toml")) {
+        Ok(config) => config.plugin_info,
+        Err(_) => return Some(source),
+    };
+    if !package.plugin_type.is_sourced() {
+        return None;
+    }
+    if !package.lazy {
+        return Some(source);
+    }
+    let functions = package.functions.unwrap_or_default();
+    if functions.is_empty() {
+        eprintln!(
+            "{} {} {}",
+            "Module".yellow(),
+            module.green(),
+            "is lazy, but declares no functions. Sourcing it on startup".yellow()
+        );
+        return Some(source);
+    }
+    let wrappers = functions
+        .iter()
+        .map(|function| {
+            format!(
+                "{}() {{ unset -f {}; {}; {} \"$@\"; }}",
+                function,
+                functions.join(" "),
+                source,
+                function
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    Some(wrappers)
+}
+
 use std::fmt::Write;
+pub static FISH_SOURCE_FILE: &str = "env.fish";
 pub fn update_source_file(global_config: &GlobalConfig) -> std::io::Result<()> {
     let base = &global_config.home;
     let modules = get_list_of_installed_modules(base, &base.to_string_lossy())?;
     let env_path = base.join("env");
     if env_path.exists() {
-        std::fs::remove_file(&env_path).expect("Cannot delete file");
+        filesystem::remove_file(global_config, &env_path).expect("Cannot delete file");
     }
-    let mut mapped_values: String = modules
+    let modules = modules
+        .into_iter()
+        .map(|module| module.replace("/script.sh", ""))
+        .filter(|module| !global_config.disabled_modules.contains(module))
+        .collect();
+    let mut mapped_values: String = load_order(global_config, modules)
         .into_iter()
-        .map(|val| format!("source {}", base.join(val).to_string_lossy()))
+        .filter_map(|val| source_lines(global_config, &val))
         .collect::<Vec<String>>()
         .join("\n");
     write!(
         mapped_values,
         "\nexport FPATH=\"{}:$FPATH\"\n",
-        &base.join("completion").to_string_lossy()
+        &base.join(COMPLETION_DIR).to_string_lossy()
     )
-    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    .map_err(std::io::Error::other)?;
+    if base.join(BIN_DIR).is_dir() {
+        writeln!(
+            mapped_values,
+            "export PATH=\"{}:$PATH\"",
+            &base.join(BIN_DIR).to_string_lossy()
+        )
+        .map_err(std::io::Error::other)?;
+    }
+    let bash_completions = base.join(COMPLETION_DIR).join("bash");
+    if bash_completions.is_dir() {
+        write!(
+            mapped_values,
+            "if [ -n \"$BASH_VERSION\" ]; then\n  for completion in \"{}\"/*; do source \"$completion\"; done\nfi\n",
+            bash_completions.to_string_lossy()
+        )
+        .map_err(std::io::Error::other)?;
+    }
     // mapped_values.push_str(&format!());
-    std::fs::write(env_path, mapped_values)?;
-    Ok(())
+    filesystem::write(global_config, &env_path, mapped_values)?;
+    update_fish_source_file(global_config)
+}
+
+/// fish can't source the `env` file, so the fish completions are added to `fish_complete_path`
+/// by `env.fish`, which only exists while there are any.
+fn update_fish_source_file(global_config: &GlobalConfig) -> std::io::Result<()> {
+    let base = &global_config.home;
+    let env_path = base.join(FISH_SOURCE_FILE);
+    let fish_completions = base.join(COMPLETION_DIR).join("fish");
+    let has_completions = std::fs::read_dir(&fish_completions)
+        .is_ok_and(|mut completions| completions.next().is_some());
+    if !has_completions {
+        if env_path.exists() {
+            filesystem::remove_file(global_config, &env_path)?;
+        }
+        return Ok(());
+    }
+    let mut mapped_values = format!(
+        "set -g fish_complete_path \"{}\" $fish_complete_path\n",
+        fish_completions.to_string_lossy()
+    );
+    if base.join(BIN_DIR).is_dir() {
+        writeln!(
+            mapped_values,
+            "set -gx PATH \"{}\" $PATH",
+            &base.join(BIN_DIR).to_string_lossy()
+        )
+        .map_err(std::io::Error::other)?;
+    }
+    filesystem::write(global_config, &env_path, mapped_values)
 }
 
 pub fn print_diff(left: &str, right: &str) {
@@ -122,6 +320,83 @@ pub fn print_diff(left: &str, right: &str) {
     }
 }
 
+const DIFF_CONTEXT: usize = 3;
+
+/// Prints a unified diff between `left` and `right`. Returns `false` if there are no changes.
+pub fn print_unified_diff(left_name: &str, right_name: &str, left: &str, right: &str) -> bool {
+    let left_lines: Vec<&str> = left.lines().collect();
+    let right_lines: Vec<&str> = right.lines().collect();
+    let lines = diff::slice(&left_lines, &right_lines);
+    let mut hunks: Vec<(usize, usize)> = vec![];
+    for (index, line) in lines.iter().enumerate() {
+        if let diff::Result::Both(_, _) = line {
+            continue;
+        }
+        let start = index.saturating_sub(DIFF_CONTEXT);
+        let end = (index + DIFF_CONTEXT + 1).min(lines.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+    if hunks.is_empty() {
+        return false;
+    }
+    println!("{}", format!("--- {}", left_name).bold());
+    println!("{}", format!("+++ {}", right_name).bold());
+    for (start, end) in hunks {
+        let left_start = lines[..start]
+            .iter()
+            .filter(|l| !matches!(l, diff::Result::Right(_)))
+            .count();
+        let right_start = lines[..start]
+            .iter()
+            .filter(|l| !matches!(l, diff::Result::Left(_)))
+            .count();
+        let left_len = lines[start..end]
+            .iter()
+            .filter(|l| !matches!(l, diff::Result::Right(_)))
+            .count();
+        let right_len = lines[start..end]
+            .iter()
+            .filter(|l| !matches!(l, diff::Result::Left(_)))
+            .count();
+        println!(
+            "{}",
+            format!(
+                "@@ -{},{} +{},{} @@",
+                left_start + 1,
+                left_len,
+                right_start + 1,
+                right_len
+            )
+            .cyan()
+        );
+        for line in &lines[start..end] {
+            match line {
+                diff::Result::Left(l) => println!("{}", format!("-{}", l).red()),
+                diff::Result::Both(l, _) => println!(" {}", l),
+                diff::Result::Right(r) => println!("{}", format!("+{}", r).green()),
+            }
+        }
+    }
+    true
+}
+
+/// Like `print_unified_diff`, but only reports binary files as differing.
+pub fn print_file_diff(left_name: &str, right_name: &str, left: &[u8], right: &[u8]) -> bool {
+    if left == right {
+        return false;
+    }
+    match (std::str::from_utf8(left), std::str::from_utf8(right)) {
+        (Ok(left), Ok(right)) => {
+            print_unified_diff(left_name, right_name, left, right);
+        }
+        _ => println!("Binary files {} and {} differ", left_name, right_name),
+    }
+    true
+}
+
 pub fn remove(global_config: &GlobalConfig, plugin_name: &str) {
     let home_path = global_config.home.join(plugin_name);
     if !home_path.exists() {
@@ -132,7 +407,16 @@ pub fn remove(global_config: &GlobalConfig, plugin_name: &str) {
         );
         std::process::exit(1);
     }
-    std::fs::remove_dir_all(home_path).expect("Could not remove directory");
+    if !run_pre_remove_hook(global_config, plugin_name) {
+        std::process::exit(1);
+    }
+    rust_package::uninstall_rust_package(global_config, plugin_name);
+    filesystem::remove_dir_all(global_config, &home_path).expect("Could not remove directory");
+    remove_binaries(global_config, plugin_name, &[]);
+    if remove_completions(global_config, plugin_name) {
+        return;
+    }
+    // modules without declared completions used to install `_<name>` themselves
     let mut file_name = plugin_name
         .to_string()
         .split_once('/')
@@ -140,9 +424,117 @@ pub fn remove(global_config: &GlobalConfig, plugin_name: &str) {
         .1
         .to_string();
     file_name.insert(0, '_');
-    let file_path = global_config.home.join("completion").join(&file_name);
+    let registry = read_registry(&global_config.home);
+    if registry.contains_key(&format!("zsh/{}", file_name)) {
+        return;
+    }
+    let file_path = global_config.home.join(COMPLETION_DIR).join(&file_name);
     if file_path.exists() {
-        std::fs::remove_file(file_path).expect("Could not remove autocompletion file");
+        filesystem::remove_file(global_config, &file_path)
+            .expect("Could not remove autocompletion file");
+    }
+}
+
+/// Enables or disables an installed module. Disabled modules keep their files and data and are
+/// still updated, they are only left out of the `env` file.
+pub fn set_enabled(global_config: &mut GlobalConfig, plugin_name: &str, enabled: bool) {
+    if !global_config.home.join(plugin_name).exists() {
+        eprintln!(
+            "{}{}",
+            "Could not find installed module ".red(),
+            plugin_name
+        );
+        std::process::exit(1);
+    }
+    let disabled = global_config
+        .disabled_modules
+        .iter()
+        .any(|module| module == plugin_name);
+    if enabled != disabled {
+        println!(
+            "{} is already {}",
+            plugin_name,
+            if enabled { "enabled" } else { "disabled" }
+        );
+        return;
+    }
+    if enabled {
+        global_config
+            .disabled_modules
+            .retain(|module| module != plugin_name);
+    } else {
+        global_config.disabled_modules.push(plugin_name.to_string());
+        global_config.disabled_modules.sort();
+        let modules = get_list_of_installed_modules(
+            &global_config.home,
+            &global_config.home.to_string_lossy(),
+        )
+        .unwrap_or_default();
+        for module in modules {
+            let module = module.replace("/script.sh", "");
+            let depends = read_config(&global_config.home.join(&module).join("config.toml"))
+                .ok()
+                .and_then(|config| config.plugin_info.internal_dependencies)
+                .is_some_and(|deps| deps.iter().any(|dep| dep == plugin_name));
+            if depends && !global_config.disabled_modules.contains(&module) {
+                println!(
+                    "{} {} {}",
+                    "Module".yellow(),
+                    module.green(),
+                    "depends on it and might not work anymore".yellow()
+                );
+            }
+        }
+    }
+    if global_config.dry_run {
+        println!(
+            "{} write {}",
+            "[dry-run]".yellow(),
+            global_config.config_path.to_string_lossy()
+        );
+    } else {
+        global_config.save().expect("Could not save global config");
+    }
+    println!(
+        "{} {}",
+        plugin_name.green(),
+        if enabled { "enabled" } else { "disabled" }
+    );
+}
+
+/// Describes everything the module would install which is already owned by another module.
+pub fn install_conflicts(
+    global_config: &GlobalConfig,
+    plugin_name: &str,
+    config: &PluginInfo,
+) -> Vec<String> {
+    let mut conflicts = completion_conflicts(global_config, plugin_name, config.completions.as_ref());
+    conflicts.extend(binary_conflicts(
+        global_config,
+        plugin_name,
+        &config.plugin_info.plugin_type.binaries(),
+    ));
+    conflicts
+}
+
+/// Installs the rust package or prebuilt binary of the module, if it has one.
+pub fn install_package(
+    global_config: &GlobalConfig,
+    plugin_name: &str,
+    plugin_type: &PluginType,
+    path_to_module: &Path,
+) -> Result<(), String> {
+    match plugin_type {
+        PluginType::RustPackage { .. } => rust_package::install_rust_package(
+            global_config,
+            plugin_name,
+            plugin_type,
+            path_to_module,
+        ),
+        PluginType::Prebuilt { .. } => {
+            prebuilt::install_prebuilt(global_config, plugin_name, plugin_type, path_to_module)
+        }
+        _ => Ok(()),
     }
 }
 
@@ -169,6 +561,11 @@ pub fn check_module_state(
     if config != new_config {
         return ModuleState::NeedsUpdate(UpdateReason::TomlChanged);
     }
+    if let Ok(sources) = read_template_sources(git_repo, plugin_name, &new_config) {
+        if sources != read_installed_sources(&home_path) {
+            return ModuleState::NeedsUpdate(UpdateReason::TemplateChanged);
+        }
+    }
     let old_script = home_path.join("script.sh");
     let new_script = Path::new(&git_repo).join(plugin_name).join("template.sh");
     if (!old_script.exists() && !new_script.exists())
@@ -184,3 +581,48 @@ pub fn get_old_script(global_config: &GlobalConfig, plugin_name: &str) -> String
     let home_path = global_config.home.join(plugin_name);
     std::fs::read_to_string(home_path.join("script.sh")).expect("Old script was not existent")
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn orders_by_dependencies_and_load_hints() {
+        let home = std::env::temp_dir().join(format!("tm-load-order-{}", std::process::id()));
+        for (module, constraint) in [
+            ("zsh/base", ""),
+            ("zsh/app", "internal_dependencies = [\"zsh/base\", \"zsh/missing\"]"),
+            ("zsh/aaa", "load_after = [\"zsh/app\"]"),
+            ("zsh/zzz", "load_before = [\"zsh/base\"]"),
+            ("x/one", "load_after = [\"x/two\"]"),
+            ("x/two", "load_after = [\"x/one\"]"),
+        ] {
+            std::fs::create_dir_all(home.join(module)).unwrap();
+            std::fs::write(
+                home.join(module).join("config.toml"),
+                format!(
+                    "[plugin_info]\nauthor = \"a\"\nversion = \"1.0.0\"\nplugin_type = \"zsh\"\n{}\n",
+                    constraint
+                ),
+            )
+            .unwrap();
+        }
+        let global_config: GlobalConfig = toml::from_str(&format!(
+            "config_path = \"/nonexistent\"\ngit_repo = \"/nonexistent\"\nhome = {:?}",
+            home
+        ))
+        .unwrap();
+        let order = |modules: &[&str]| {
+            load_order(
+                &global_config,
+                modules.iter().map(|module| module.to_string()).collect(),
+            )
+        };
+        let sorted = order(&["zsh/base", "zsh/app", "zsh/aaa", "zsh/zzz"]);
+        // a cycle is reported and broken by name instead of dropping modules
+        let cyclic = order(&["x/two", "x/one"]);
+        let _ = std::fs::remove_dir_all(&home);
+        assert_eq!(sorted, vec!["zsh/zzz", "zsh/base", "zsh/app", "zsh/aaa"]);
+        assert_eq!(cyclic, vec!["x/one", "x/two"]);
+    }
+}
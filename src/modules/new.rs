@@ -0,0 +1,179 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! `new` scaffolds a module in the git repo with a valid `config.toml` and a `template.sh`.
+
+use std::path::Path;
+
+use colored::Colorize;
+
+use crate::{
+    filesystem,
+    models::{GlobalConfig, PluginInfo},
+    prompts::{boolean_prompt, select_prompt, text_prompt},
+};
+
+static PLUGIN_TYPES: [&str; 5] = [
+    "zsh (sourced by the shell)",
+    "bash (sourced by the shell)",
+    "script (executable in the bin directory)",
+    "rust package (cargo install)",
+    "prebuilt binary (download with checksum)",
+];
+
+/// `Name <email>` from the git config of the module repo.
+fn git_author(git_repo: &str) -> Option<String> {
+    let config = match git2::Repository::discover(git_repo) {
+        Ok(repo) => repo.config().ok()?,
+        Err(_) => git2::Config::open_default().ok()?,
+    };
+    let name = config.get_string("user.name").ok()?;
+    Some(match config.get_string("user.email") {
+        Ok(email) => format!("{} <{}>", name, email),
+        Err(_) => name,
+    })
+}
+
+fn quoted(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+/// Creates the module `plugin_name` in the git repo after asking for the help text, the plugin
+/// type and which examples to include.
+pub fn new_module(global_config: &GlobalConfig, git_repo: &str, plugin_name: &str) {
+    let name = match plugin_name.split_once('/') {
+        Some((_, name)) if !name.is_empty() => name.rsplit('/').next().unwrap_or(name),
+        _ => {
+            eprintln!(
+                "{}",
+                "Modules are named <group>/<name>, e.g. zsh/docker".red()
+            );
+            std::process::exit(1);
+        }
+    };
+    let path_to_module = Path::new(git_repo).join(plugin_name);
+    if path_to_module.exists() {
+        eprintln!("{} {:?}", "Module already exists at".red(), path_to_module);
+        std::process::exit(1);
+    }
+
+    let author = git_author(git_repo).unwrap_or_else(|| {
+        println!("{}", "No user.name in the git config".yellow());
+        "TODO".to_string()
+    });
+    let help = text_prompt("Help text").unwrap_or_default();
+    let plugin_type = select_prompt("Plugin type", PLUGIN_TYPES.to_vec());
+    let with_placeholders = boolean_prompt("Add placeholder examples?");
+    let with_supporting_files = boolean_prompt("Add supporting file examples?");
+
+    let mut config = format!(
+        "[plugin_info]\nauthor = {}\nversion = \"0.1.0\"\nhelp = {}\n\
+         # insert values as they are instead of HTML escaping them\n\
+         unescaped = true\n",
+        quoted(&author),
+        quoted(&help)
+    );
+    let mut files: Vec<(String, String)> = vec![];
+    let mut template = String::new();
+    match PLUGIN_TYPES.iter().position(|t| *t == plugin_type) {
+        Some(0) => config.push_str("plugin_type = \"zsh\"\n"),
+        Some(1) => config.push_str("plugin_type = \"bash\"\n"),
+        Some(2) => {
+            config.push_str(&format!(
+                "plugin_type = {{ script = {}, interpreter = \"bash\" }}\n",
+                quoted(name)
+            ));
+            template.push_str("#!/usr/bin/env bash\nset -euo pipefail\n\n");
+        }
+        Some(3) => {
+            config.push_str(
+                "# or { git = \"https://github.com/<owner>/<repo>\", tag = \"v0.1.0\" }\n\
+             plugin_type = { path = \"crate\" }\n",
+            );
+            let manifest = format!(
+                "[package]\nname = {}\nversion = \"0.1.0\"\nedition = \"2021\"\n",
+                quoted(name)
+            );
+            files.push(("crate/Cargo.toml".to_string(), manifest));
+            files.push((
+                "crate/src/main.rs".to_string(),
+                format!("fn main() {{\n    println!(\"Hello from {}\");\n}}\n", name),
+            ));
+        }
+        _ => {
+            config.push_str(&format!(
+                "\n[plugin_info.plugin_type]\nbinary = {}\n\n\
+             # one table per target triple, `*` matches every platform\n\
+             [plugin_info.plugin_type.platforms.x86_64-unknown-linux-gnu]\n\
+             url = \"https://example.com/{}-x86_64-unknown-linux-gnu.tar.gz\"\n\
+             sha256 = \"TODO\"\n",
+                quoted(name),
+                name
+            ));
+        }
+    }
+
+    let greeting = if with_placeholders {
+        config.push_str(
+            "\n# asked for on install, use them as {{greeting}} in the templates\n\
+             [placeholders]\n\
+             greeting = \"Hello\"\n\
+             target = { type = \"string\", description = \"Who to greet\", default = \"world\", quote = \"double\" }\n",
+        );
+        "echo \"{{greeting}}\" {{target}}".to_string()
+    } else {
+        format!("echo \"Hello from {}\"", name)
+    };
+    if template.is_empty() {
+        // sourced by every shell, so only define functions and aliases
+        template = format!("{}() {{\n    {}\n}}\n", name, greeting);
+    } else {
+        template.push_str(&greeting);
+        template.push('\n');
+    }
+
+    if with_supporting_files {
+        config.push_str(
+            "\n# copied to the module directory, use the path as {{example}} in the templates\n\
+             [supporting_files]\n\
+             example = { version = \"1\", path = \"files/example.conf\" }\n\
+             # rendered with the placeholders instead of copied\n\
+             # example_template = { version = \"1\", path = \"files/example.conf\", destination = \"example.rendered.conf\", template = true }\n",
+        );
+        files.push((
+            "files/example.conf".to_string(),
+            "# supporting file of the module\n".to_string(),
+        ));
+    }
+
+    toml::from_str::<PluginInfo>(&config).expect("generated an invalid config.toml");
+    let has_todos = config.contains("\"TODO\"");
+    files.insert(0, ("template.sh".to_string(), template));
+    files.insert(0, ("config.toml".to_string(), config));
+    for (file, contents) in files {
+        let path = path_to_module.join(file);
+        if let Some(parent) = path.parent() {
+            filesystem::create_dir_all(global_config, parent).expect("Could not create directory");
+        }
+        filesystem::write(global_config, &path, contents).expect("Could not write file");
+        if !global_config.dry_run {
+            println!("Created {:?}", path);
+        }
+    }
+    if has_todos {
+        println!(
+            "{}",
+            "Replace the TODO values in config.toml, `terminal-magic validate` reports them"
+                .yellow()
+        );
+    }
+    println!(
+        "{} {}",
+        "Install it with".green(),
+        format!("terminal-magic install {}", plugin_name)
+            .green()
+            .bold()
+    );
+}
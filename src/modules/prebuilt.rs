@@ -0,0 +1,270 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! `Prebuilt` modules install an executable, or an executable out of a `.tar.gz` or `.zip`
+//! archive, for the current platform into the `bin` directory of terminal-magic.
+
+use std::{
+    ffi::OsStr,
+    io::{Cursor, Read},
+    path::Path,
+    process::Command,
+};
+
+use colored::Colorize;
+use flate2::read::GzDecoder;
+use indexmap::IndexMap;
+use url::Url;
+
+use crate::{
+    binaries::install_binary,
+    filesystem,
+    models::{GlobalConfig, PluginType, PrebuiltSource},
+};
+
+/// The platform the binary was built for, e.g. `x86_64-unknown-linux-gnu`.
+pub static TARGET: &str = env!("TARGET");
+
+fn arch_and_os(triple: &str) -> (Option<&str>, Option<&str>) {
+    let mut parts = triple.split('-');
+    (parts.next(), parts.nth(1))
+}
+
+/// Picks the source for this platform: the exact target triple, a triple with the same
+/// architecture and operating system (e.g. `musl` instead of `gnu`), or `*`.
+fn select_source(platforms: &IndexMap<String, PrebuiltSource>) -> Option<&PrebuiltSource> {
+    platforms
+        .get(TARGET)
+        .or_else(|| {
+            platforms
+                .iter()
+                .find(|(triple, _)| arch_and_os(triple) == arch_and_os(TARGET))
+                .map(|(_, source)| source)
+        })
+        .or_else(|| platforms.get("*"))
+}
+
+/// Seconds a download may take before it is aborted.
+static DOWNLOAD_TIMEOUT: u32 = 600;
+
+/// Reads or downloads the source. Returns `None` instead of downloading in dry-run mode.
+fn fetch(
+    global_config: &GlobalConfig,
+    source: &PrebuiltSource,
+    path_to_module: &Path,
+) -> Result<Option<(String, Vec<u8>)>, String> {
+    let url = match (&source.path, &source.url) {
+        (Some(path), _) => {
+            return std::fs::read(path_to_module.join(path))
+                .map(|contents| Some((path.to_owned(), contents)))
+                .map_err(|err| format!("could not read {}: {}", path, err))
+        }
+        (None, Some(url)) => url,
+        (None, None) => return Err("either path or url should be set".to_string()),
+    };
+    let parsed = Url::parse(url).map_err(|err| format!("invalid url {}: {}", url, err))?;
+    match parsed.scheme() {
+        "file" => {
+            let path = parsed
+                .to_file_path()
+                .map_err(|_| format!("invalid file url {}", url))?;
+            std::fs::read(path)
+                .map(|contents| Some((url.to_owned(), contents)))
+                .map_err(|err| format!("could not read {}: {}", url, err))
+        }
+        "https" if global_config.dry_run => {
+            println!("{} download {}", "[dry-run]".yellow(), url);
+            Ok(None)
+        }
+        "https" => {
+            println!("Downloading {}", url);
+            // redirects must stay on https, e.g. to a CDN
+            let output = Command::new("curl")
+                .arg("--fail")
+                .arg("--silent")
+                .arg("--show-error")
+                .arg("--location")
+                .arg("--max-redirs")
+                .arg("10")
+                .arg("--proto")
+                .arg("=https")
+                .arg("--proto-redir")
+                .arg("=https")
+                .arg("--max-time")
+                .arg(DOWNLOAD_TIMEOUT.to_string())
+                .arg(url)
+                .output()
+                .map_err(|err| format!("could not run curl: {}", err))?;
+            if !output.status.success() {
+                return Err(format!(
+                    "could not download {}: {}",
+                    url,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            Ok(Some((url.to_owned(), output.stdout)))
+        }
+        scheme => Err(format!(
+            "unsupported url {}: {}:// is neither https:// nor file://",
+            url, scheme
+        )),
+    }
+}
+
+/// Returns the executable out of `contents`, unpacking it if `name` is an archive.
+fn extract(
+    name: &str,
+    contents: Vec<u8>,
+    file: Option<&str>,
+    binary: &str,
+) -> Result<Vec<u8>, String> {
+    let is_wanted = |entry: &Path| match file {
+        Some(file) => entry == Path::new(file.trim_start_matches("./")),
+        None => entry.file_name() == Some(OsStr::new(binary)),
+    };
+    let not_found = || format!("could not find {} in {}", file.unwrap_or(binary), name);
+    // the query and fragment of a url, e.g. `?raw=true`, aren't part of the file name
+    let file_name = match Url::parse(name) {
+        Ok(url) => url.path().to_lowercase(),
+        Err(_) => name.to_lowercase(),
+    };
+    if file_name.ends_with(".tar.gz") || file_name.ends_with(".tgz") {
+        let mut archive = tar::Archive::new(GzDecoder::new(&contents[..]));
+        let entries = archive
+            .entries()
+            .map_err(|err| format!("could not unpack {}: {}", name, err))?;
+        for entry in entries {
+            let mut entry = entry.map_err(|err| format!("could not unpack {}: {}", name, err))?;
+            let path = match entry.path() {
+                Ok(path) => path.strip_prefix("./").unwrap_or(&path).to_path_buf(),
+                Err(_) => continue,
+            };
+            if entry.header().entry_type().is_file() && is_wanted(&path) {
+                let mut executable = vec![];
+                entry
+                    .read_to_end(&mut executable)
+                    .map_err(|err| format!("could not unpack {}: {}", name, err))?;
+                return Ok(executable);
+            }
+        }
+        Err(not_found())
+    } else if file_name.ends_with(".zip") {
+        let mut archive = zip::ZipArchive::new(Cursor::new(contents))
+            .map_err(|err| format!("could not unpack {}: {}", name, err))?;
+        for index in 0..archive.len() {
+            let mut entry = archive
+                .by_index(index)
+                .map_err(|err| format!("could not unpack {}: {}", name, err))?;
+            let path = match entry.enclosed_name() {
+                Some(path) => path.to_path_buf(),
+                None => continue,
+            };
+            if entry.is_file() && is_wanted(&path) {
+                let mut executable = vec![];
+                entry
+                    .read_to_end(&mut executable)
+                    .map_err(|err| format!("could not unpack {}: {}", name, err))?;
+                return Ok(executable);
+            }
+        }
+        Err(not_found())
+    } else {
+        Ok(contents)
+    }
+}
+
+/// Fetches the source for this platform, verifies its checksum and installs the executable.
+pub fn install_prebuilt(
+    global_config: &GlobalConfig,
+    plugin_name: &str,
+    plugin_type: &PluginType,
+    path_to_module: &Path,
+) -> Result<(), String> {
+    let (binary, platforms) = match plugin_type {
+        PluginType::Prebuilt { binary, platforms } => (binary, platforms),
+        _ => return Ok(()),
+    };
+    let source =
+        select_source(platforms).ok_or_else(|| format!("no prebuilt {} for {}", binary, TARGET))?;
+    let (name, contents) = match fetch(global_config, source, path_to_module)? {
+        Some(fetched) => fetched,
+        // nothing was downloaded which could be verified, only print where it would go
+        None => {
+            return install_binary(global_config, plugin_name, binary, &[], 0o755)
+                .map_err(|err| format!("could not install {}: {}", binary, err))
+        }
+    };
+    let checksum = filesystem::sha256(&contents);
+    if !checksum.eq_ignore_ascii_case(source.sha256.trim()) {
+        return Err(format!(
+            "checksum mismatch for {}: expected {}, got {}",
+            name, source.sha256, checksum
+        ));
+    }
+    let executable = extract(&name, contents, source.file.as_deref(), binary)?;
+    install_binary(global_config, plugin_name, binary, &executable, 0o755)
+        .map_err(|err| format!("could not install {}: {}", binary, err))?;
+    if !global_config.dry_run {
+        println!("Installed {}", binary);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(url: &str) -> PrebuiltSource {
+        toml::from_str(&format!("url = \"{}\"\nsha256 = \"\"", url)).unwrap()
+    }
+
+    #[test]
+    fn fetches_percent_encoded_file_urls_and_rejects_http() {
+        let global_config: GlobalConfig =
+            toml::from_str("config_path = \"/nonexistent\"\ngit_repo = \"/nonexistent\"").unwrap();
+        let dir = std::env::temp_dir().join(format!("tm prebuilt {}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("tool"), "#!/bin/sh\n").unwrap();
+        let url = Url::from_file_path(dir.join("tool")).unwrap().to_string();
+        let fetched = fetch(&global_config, &source(&url), Path::new("/nonexistent"));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(url.contains("%20"));
+        assert_eq!(fetched, Ok(Some((url, b"#!/bin/sh\n".to_vec()))));
+        assert!(fetch(
+            &global_config,
+            &source("http://example.com/tool"),
+            Path::new("/nonexistent")
+        )
+        .unwrap_err()
+        .starts_with("unsupported url"));
+    }
+
+    #[test]
+    fn unpacks_archives_from_urls_with_a_query() {
+        let mut archive = zip::ZipWriter::new(Cursor::new(vec![]));
+        archive
+            .start_file("tool-1.0/tool", zip::write::FileOptions::default())
+            .unwrap();
+        std::io::Write::write_all(&mut archive, b"#!/bin/sh\n").unwrap();
+        let contents = archive.finish().unwrap().into_inner();
+        for name in [
+            "https://cdn.example.com/tool.ZIP?X-Amz-Signature=abc",
+            "https://example.com/tool.zip#sha",
+            "dist/tool.zip",
+        ] {
+            assert_eq!(
+                extract(name, contents.clone(), None, "tool"),
+                Ok(b"#!/bin/sh\n".to_vec()),
+                "{}",
+                name
+            );
+        }
+        // not an archive, installed as it is
+        assert_eq!(
+            extract("https://example.com/tool?archive.zip", b"x".to_vec(), None, "tool"),
+            Ok(b"x".to_vec())
+        );
+    }
+}
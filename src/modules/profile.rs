@@ -0,0 +1,106 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use colored::Colorize;
+
+use crate::models::GlobalConfig;
+
+use super::{get_list_of_installed_modules, load_order, read_config};
+
+const RUNS: usize = 3;
+
+/// The fastest of a few runs of `shell -c` sourcing the `scripts`, or `None` if it failed.
+fn time_sourcing(shell: &str, scripts: &[PathBuf]) -> Option<Duration> {
+    let command = scripts
+        .iter()
+        .map(|script| format!("source '{}'", script.to_string_lossy().replace('\'', "'\\''")))
+        .chain(std::iter::once(String::from(":")))
+        .collect::<Vec<String>>()
+        .join("; ");
+    (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            let status = Command::new(shell)
+                .arg("-c")
+                .arg(&command)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .ok()?;
+            let elapsed = start.elapsed();
+            status.success().then_some(elapsed)
+        })
+        .collect::<Option<Vec<Duration>>>()
+        .and_then(|durations| durations.into_iter().min())
+}
+
+/// Times sourcing every installed module in a subshell and prints them, slowest first. The
+/// internal dependencies of a module are sourced before it and their time is subtracted.
+pub fn profile(global_config: &GlobalConfig, shell: &str) {
+    let modules = get_list_of_installed_modules(
+        &global_config.home,
+        &global_config.home.to_string_lossy(),
+    )
+    .unwrap_or_default()
+    .into_iter()
+    .map(|module| module.replace("/script.sh", ""))
+    .collect();
+    let script = |module: &str| global_config.home.join(module).join("script.sh");
+    let mut timings = vec![];
+    for module in load_order(global_config, modules) {
+        let package = read_config(&global_config.home.join(&module).join("config.toml"))
+            .ok()
+            .map(|config| config.plugin_info);
+        if package
+            .as_ref()
+            .is_some_and(|package| !package.plugin_type.is_sourced())
+        {
+            continue;
+        }
+        let dependencies: Vec<PathBuf> = package
+            .as_ref()
+            .and_then(|package| package.internal_dependencies.as_ref())
+            .into_iter()
+            .flatten()
+            .map(|dep| script(dep))
+            .filter(|script| script.exists())
+            .collect();
+        let mut with_module = dependencies.clone();
+        with_module.push(script(&module));
+        let timing = time_sourcing(shell, &with_module).map(|total| {
+            let base = time_sourcing(shell, &dependencies).unwrap_or_default();
+            total.saturating_sub(base)
+        });
+        let mut labels = vec![];
+        if package.as_ref().is_some_and(|package| package.lazy) {
+            labels.push("lazy");
+        }
+        if global_config.disabled_modules.contains(&module) {
+            labels.push("disabled");
+        }
+        timings.push((module, timing, labels));
+    }
+    timings.sort_by_key(|timing| std::cmp::Reverse(timing.1));
+    println!("Sourcing with {}, fastest of {} runs:", shell.green(), RUNS);
+    for (module, timing, labels) in timings {
+        let timing = match timing {
+            Some(timing) => format!("{:>8.1} ms", timing.as_secs_f64() * 1000.0).normal(),
+            None => format!("{:>11}", "failed").red(),
+        };
+        let labels = if labels.is_empty() {
+            String::new()
+        } else {
+            format!(" ({})", labels.join(", "))
+        };
+        println!("{}  {}{}", timing, module.blue(), labels.yellow());
+    }
+}
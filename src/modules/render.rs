@@ -0,0 +1,144 @@
+use std::path::{Path, PathBuf};
+
+use colored::Colorize;
+use indexmap::IndexMap;
+
+use crate::{
+    models::{FileSystemEntry, GlobalConfig},
+    secrets::{installed_secret_values, mask},
+    template::{
+        add_files_as_vars, add_placeholders_as_vars, compile_template, lint_quoting, map_builder,
+        render, render_outputs,
+    },
+};
+
+use super::{install::glob_files, get_old_script, print_file_diff, print_unified_diff, read_config, read_data};
+
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+/// Renders the template and the templated supporting files from the git repo with the data of the
+/// installed module, without writing anything to disk.
+pub fn render_module(
+    global_config: &GlobalConfig,
+    git_repo: &str,
+    plugin_name: &str,
+) -> (String, Vec<(PathBuf, String)>) {
+    let home_path = global_config.home.join(plugin_name);
+    if !home_path.exists() {
+        eprintln!("module is not installed");
+        std::process::exit(1);
+    }
+    let path_to_module = Path::new(git_repo).join(plugin_name);
+    if !path_to_module.exists() {
+        eprintln!(
+            "{}",
+            "Could not find module in the git repo. Did you execute `git pull`?".red()
+        );
+        std::process::exit(1);
+    }
+    let toml = read_data(&home_path).expect("Cannot find TOML");
+    let new_config =
+        read_config(&path_to_module.join("config.toml")).expect("module config not found");
+    let unescaped = new_config.plugin_info.unescaped;
+    let mustache = compile_template(git_repo, plugin_name, "template.sh", unescaped);
+
+    let mut mustache_map_builder = map_builder();
+    if let Some(placeholders) = &toml.placeholders {
+        mustache_map_builder =
+            match add_placeholders_as_vars(placeholders, &new_config, mustache_map_builder) {
+                Ok(mustache_map_builder) => mustache_map_builder,
+                Err(err) => {
+                    eprintln!("{}", err.red());
+                    std::process::exit(1);
+                }
+            };
+        lint_quoting(git_repo, plugin_name, "template.sh", &new_config, placeholders);
+    }
+    if let Some(files) = &new_config.supporting_files {
+        mustache_map_builder = add_files_as_vars(files, mustache_map_builder, &home_path);
+    }
+    let mustache_map = mustache_map_builder.build();
+    let outputs = match &new_config.supporting_files {
+        Some(files) => {
+            render_outputs(git_repo, plugin_name, files, &home_path, &mustache_map, unescaped)
+        }
+        None => vec![],
+    };
+    (render(mustache, &mustache_map), outputs)
+}
+
+/// Shows what an update would change in `script.sh` and the supporting files.
+pub fn diff_module(global_config: &GlobalConfig, git_repo: &str, plugin_name: &str) {
+    let (script, outputs) = render_module(global_config, git_repo, plugin_name);
+    let home_path = global_config.home.join(plugin_name);
+    let path_to_module = Path::new(git_repo).join(plugin_name);
+    let secrets = installed_secret_values(&home_path);
+    let mut changed = print_unified_diff(
+        &home_path.join("script.sh").to_string_lossy(),
+        &path_to_module.join("template.sh").to_string_lossy(),
+        &mask(&get_old_script(global_config, plugin_name), &secrets),
+        &mask(&script, &secrets),
+    );
+    let new_config =
+        read_config(&path_to_module.join("config.toml")).expect("module config not found");
+    for (destination, output) in outputs {
+        let old_output = std::fs::read_to_string(&destination).unwrap_or_default();
+        changed |= print_unified_diff(
+            &destination.to_string_lossy(),
+            &destination.to_string_lossy(),
+            &mask(&old_output, &secrets),
+            &mask(&output, &secrets),
+        );
+    }
+    if let Some(files) = &new_config.supporting_files {
+        changed |= diff_supporting_files(files, &path_to_module, &home_path);
+    }
+    if !changed {
+        println!("{}", "No differences".green());
+    }
+}
+
+fn diff_supporting_files(
+    files: &IndexMap<String, FileSystemEntry>,
+    path_to_module: &Path,
+    cwd: &Path,
+) -> bool {
+    let mut changed = false;
+    for file in files.values() {
+        let destination = file.destination(cwd);
+        match file {
+            FileSystemEntry::File { template: true, .. } => {}
+            FileSystemEntry::File { path, .. } => {
+                let source = path_to_module.join(path);
+                let old = std::fs::read(&destination).unwrap_or_default();
+                let new = std::fs::read(&source).unwrap_or_default();
+                changed |= print_file_diff(
+                    &destination.to_string_lossy(),
+                    &source.to_string_lossy(),
+                    &old,
+                    &new,
+                );
+            }
+            FileSystemEntry::Directory {
+                path, files, glob, ..
+            } => {
+                changed |= diff_supporting_files(files, path_to_module, &destination);
+                let source_dir = path_to_module.join(path);
+                for relative in glob_files(&source_dir, glob.as_deref()) {
+                    let target = destination.join(&relative);
+                    let source = source_dir.join(&relative);
+                    changed |= print_file_diff(
+                        &target.to_string_lossy(),
+                        &source.to_string_lossy(),
+                        &std::fs::read(&target).unwrap_or_default(),
+                        &std::fs::read(&source).unwrap_or_default(),
+                    );
+                }
+            }
+        }
+    }
+    changed
+}
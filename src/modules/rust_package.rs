@@ -0,0 +1,334 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! `cargo install` for `RustPackage` modules. Packages are installed with `--root` pointing at the
+//! terminal-magic home, so the binaries end up in the `bin` directory and are owned by the module.
+
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use colored::Colorize;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    binaries::{binary_path, register_binaries, remove_binaries},
+    filesystem,
+    models::{to_toml_string, GlobalConfig, PluginType},
+    modules::read_config,
+};
+
+pub static PACKAGE_FILE: &str = "rust_package.toml";
+
+/// What was installed for the module the last time `cargo install` succeeded.
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct InstalledPackage {
+    fingerprint: Option<String>,
+    package: Option<String>,
+    #[serde(default)]
+    binaries: Vec<String>,
+}
+
+fn read_installed_package(home_path: &Path) -> InstalledPackage {
+    std::fs::read_to_string(home_path.join(PACKAGE_FILE))
+        .ok()
+        .and_then(|installed| toml::from_str(&installed).ok())
+        .unwrap_or_default()
+}
+
+/// Identifies the source of the package. Sources which can move without the config changing
+/// (a branch or the default branch of a git repo) have no fingerprint and are always handed to
+/// cargo, which decides itself whether the installed version is up to date.
+fn fingerprint(plugin_type: &PluginType, path_to_module: &Path) -> Option<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{:?}", plugin_type));
+    match plugin_type {
+        PluginType::RustPackage {
+            git: Some(_),
+            tag,
+            rev,
+            ..
+        } => {
+            if tag.is_none() && rev.is_none() {
+                return None;
+            }
+        }
+        PluginType::RustPackage {
+            path: Some(path), ..
+        } => hash_sources(&mut hasher, &path_to_module.join(path), Path::new("")),
+        _ => return None,
+    }
+    Some(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_sources(hasher: &mut Sha256, dir: &Path, relative: &Path) {
+    let mut entries: Vec<PathBuf> = match std::fs::read_dir(dir.join(relative)) {
+        Ok(entries) => entries.flatten().map(|entry| entry.path()).collect(),
+        Err(_) => return,
+    };
+    entries.sort();
+    for entry in entries {
+        let name = entry.file_name().unwrap_or_default();
+        if name == "target" || name == ".git" {
+            continue;
+        }
+        let relative = relative.join(name);
+        if entry.is_dir() {
+            hash_sources(hasher, dir, &relative);
+        } else if let Ok(contents) = std::fs::read(&entry) {
+            hasher.update(relative.to_string_lossy().as_bytes());
+            hasher.update(filesystem::sha256(&contents));
+        }
+    }
+}
+
+/// Runs `cargo install` for the module unless the same source is already installed, and records
+/// the installed binaries. Fails with a description if cargo could not be run or failed.
+pub fn install_rust_package(
+    global_config: &GlobalConfig,
+    plugin_name: &str,
+    plugin_type: &PluginType,
+    path_to_module: &Path,
+) -> Result<(), String> {
+    let (path, git, tag, branch, rev, features, bins, locked) = match plugin_type {
+        PluginType::RustPackage {
+            path,
+            git,
+            tag,
+            branch,
+            rev,
+            features,
+            bins,
+            locked,
+        } => (path, git, tag, branch, rev, features, bins, locked),
+        _ => return Ok(()),
+    };
+    let home = &global_config.home;
+    let home_path = home.join(plugin_name);
+    let installed = read_installed_package(&home_path);
+    let fingerprint = fingerprint(plugin_type, path_to_module);
+    if fingerprint.is_some()
+        && installed.fingerprint == fingerprint
+        && installed
+            .binaries
+            .iter()
+            .all(|name| binary_path(home, name).exists())
+    {
+        println!("{}", "Rust package is up to date".green());
+        return Ok(());
+    }
+
+    let mut install_command = Command::new("cargo");
+    install_command.arg("install").arg("--root").arg(home);
+    if let Some(git) = git {
+        install_command
+            .env("CARGO_NET_GIT_FETCH_WITH_CLI", "true")
+            .arg("--git")
+            .arg(git);
+        for (flag, value) in [("--tag", tag), ("--branch", branch), ("--rev", rev)].iter() {
+            if let Some(value) = value {
+                install_command.arg(flag).arg(value);
+            }
+        }
+    } else if let Some(path) = path {
+        install_command.arg("--path").arg(path_to_module.join(path));
+    } else {
+        return Err("either path or git should be set".to_string());
+    }
+    let source = source_url(plugin_type, path_to_module);
+    if let Some(features) = features {
+        install_command.arg("--features").arg(features.join(","));
+    }
+    if let Some(bins) = bins {
+        for bin in bins {
+            install_command.arg("--bin").arg(bin);
+        }
+    }
+    if *locked {
+        install_command.arg("--locked");
+    }
+    match filesystem::run(global_config, &mut install_command) {
+        Ok(_) if global_config.dry_run => {}
+        Ok(status) if status.success() => println!("Successfully installed rust-module"),
+        Ok(status) => return Err(format!("cargo install failed ({})", status)),
+        Err(err) => return Err(format!("could not run cargo: {}", err)),
+    }
+    if global_config.dry_run {
+        return Ok(());
+    }
+
+    if is_legacy_install(&home_path) {
+        uninstall_legacy_package(global_config, source.as_deref());
+    }
+    let (package, binaries) = match source.and_then(|source| installed_binaries(home, &source)) {
+        Some((package, binaries)) => (Some(package), binaries),
+        None => {
+            eprintln!(
+                "{}",
+                "Could not find the installed package in the cargo install metadata".yellow()
+            );
+            (installed.package, bins.clone().unwrap_or_default())
+        }
+    };
+    remove_binaries(global_config, plugin_name, &binaries);
+    register_binaries(global_config, plugin_name, &binaries);
+    let installed = InstalledPackage {
+        fingerprint,
+        package,
+        binaries,
+    };
+    if filesystem::write(
+        global_config,
+        &home_path.join(PACKAGE_FILE),
+        to_toml_string(&installed).expect("could not serialize rust package"),
+    )
+    .is_err()
+    {
+        eprintln!("{}", "Could not write the installed rust package".red());
+    }
+    Ok(())
+}
+
+/// The install metadata cargo keeps in `.crates2.json` under the install root.
+#[derive(Deserialize, Default)]
+struct CratesMetadata {
+    #[serde(default)]
+    installs: IndexMap<String, CrateInstall>,
+}
+
+#[derive(Deserialize)]
+struct CrateInstall {
+    #[serde(default)]
+    bins: Vec<String>,
+}
+
+/// The url of the source of the package as cargo records it, e.g. `https://github.com/o/r` or
+/// `file:///path/to/crate`.
+fn source_url(plugin_type: &PluginType, path_to_module: &Path) -> Option<String> {
+    match plugin_type {
+        PluginType::RustPackage { git: Some(git), .. } => {
+            Some(git.trim_end_matches('/').to_string())
+        }
+        PluginType::RustPackage {
+            path: Some(path), ..
+        } => {
+            let path = path_to_module.join(path);
+            let path = path.canonicalize().unwrap_or(path);
+            url::Url::from_file_path(path).ok().map(String::from)
+        }
+        _ => None,
+    }
+}
+
+/// Splits a package id like `name 0.1.0 (git+https://github.com/o/r?tag=v1#abc)` into the name
+/// and the source url without the kind, query and fragment.
+fn parse_package_id(id: &str) -> Option<(&str, &str)> {
+    let (package, source) = id.split_once(' ')?;
+    let source = source.split_once('(')?.1.trim_end_matches(')');
+    let source = source.split_once('+').map_or(source, |(_, url)| url);
+    let source = source.split(['?', '#']).next()?;
+    Some((package, source.trim_end_matches('/')))
+}
+
+/// Looks up the package installed from `source` into `root` and its binaries.
+fn installed_binaries(root: &Path, source: &str) -> Option<(String, Vec<String>)> {
+    let metadata: CratesMetadata = std::fs::read_to_string(root.join(".crates2.json"))
+        .ok()
+        .and_then(|metadata| serde_json::from_str(&metadata).ok())
+        .unwrap_or_default();
+    metadata.installs.into_iter().find_map(|(id, install)| {
+        let (package, installed_source) = parse_package_id(&id)?;
+        (installed_source == source).then(|| (package.to_string(), install.bins))
+    })
+}
+
+/// Where `cargo install` puts packages without `--root`.
+fn default_cargo_root() -> Option<PathBuf> {
+    std::env::var_os("CARGO_INSTALL_ROOT")
+        .or_else(|| std::env::var_os("CARGO_HOME"))
+        .map(PathBuf::from)
+        .or_else(|| dirs::home_dir().map(|home| home.join(".cargo")))
+}
+
+/// Whether the module was installed by a version of terminal-magic which didn't manage the
+/// package yet.
+fn is_legacy_install(home_path: &Path) -> bool {
+    home_path.join("config.toml").exists() && !home_path.join(PACKAGE_FILE).exists()
+}
+
+/// Older versions of terminal-magic installed packages into the default cargo root. Removes the
+/// package installed from the same source there, so only the managed copy is left.
+fn uninstall_legacy_package(global_config: &GlobalConfig, source: Option<&str>) {
+    let root = match default_cargo_root() {
+        Some(root) if root != global_config.home => root,
+        _ => return,
+    };
+    let package = match source.and_then(|source| installed_binaries(&root, source)) {
+        Some((package, _)) => package,
+        None => return,
+    };
+    println!(
+        "Removing {} installed into {:?} by an older terminal-magic",
+        package, root
+    );
+    uninstall(global_config, &root, &package);
+}
+
+fn uninstall(global_config: &GlobalConfig, root: &Path, package: &str) {
+    let mut uninstall_command = Command::new("cargo");
+    uninstall_command
+        .arg("uninstall")
+        .arg("--root")
+        .arg(root)
+        .arg(package);
+    match filesystem::run(global_config, &mut uninstall_command) {
+        Ok(status) if status.success() => {}
+        _ => eprintln!(
+            "{} {}",
+            "Could not uninstall rust package".yellow(),
+            package
+        ),
+    }
+}
+
+/// Uninstalls the package of a removed module with `cargo uninstall`. Has to run before the
+/// module directory is removed.
+pub fn uninstall_rust_package(global_config: &GlobalConfig, plugin_name: &str) {
+    let home_path = global_config.home.join(plugin_name);
+    match read_installed_package(&home_path).package {
+        Some(package) => uninstall(global_config, &global_config.home, &package),
+        None if is_legacy_install(&home_path) => {
+            let plugin_type = match read_config(&home_path.join("config.toml")) {
+                Ok(config) => config.plugin_info.plugin_type,
+                Err(_) => return,
+            };
+            let path_to_module = Path::new(&global_config.git_repo).join(plugin_name);
+            let source = source_url(&plugin_type, &path_to_module);
+            uninstall_legacy_package(global_config, source.as_deref());
+        }
+        None => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_package_id;
+
+    #[test]
+    fn parses_git_and_path_package_ids() {
+        assert_eq!(
+            parse_package_id("tool 1.2.0 (git+https://github.com/o/tool/?tag=v1.2.0#0123abcd)"),
+            Some(("tool", "https://github.com/o/tool"))
+        );
+        assert_eq!(
+            parse_package_id("local 0.1.0 (path+file:///repo/tools/local/crate)"),
+            Some(("local", "file:///repo/tools/local/crate"))
+        );
+        assert_eq!(parse_package_id("broken"), None);
+    }
+}
@@ -1,12 +1,11 @@
-use std::{path::Path, process::Command};
+use std::path::Path;
 
 use colored::Colorize;
 use indexmap::IndexMap;
-use mustache::MapBuilder;
 
-use crate::{models::{GlobalConfig, ModuleState, EntryType, PluginType}, prompts::{boolean_prompt, read, read_array, get_short_names}, modules::print_diff, template::{add_files_as_vars, render}};
+use crate::{hooks::{run_module_hook, save_pre_remove_hook}, models::{to_toml_string, GlobalConfig, ModuleState, EntryType}, secrets::{find_secrets, installed_secret_values, mask, mask_config}, prompts::{self, boolean_prompt, read, read_array}, modules::print_diff, template::{add_files_as_vars, add_placeholders_as_vars, check_strict, compile_template, lint_quoting, map_builder, render, render_outputs}};
 
-use super::{read_config, check_module_state, install::{install, write_file}, get_old_script};
+use super::{install_conflicts, install_package, read_config, read_data, check_module_state, install::{install, write_file, write_supporting_files}, get_old_script};
 
 // Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
 //
@@ -19,6 +18,7 @@ pub fn update(
     plugin_name: &str,
     fail_on_error: bool,
     silent: bool,
+    reconfigure: bool,
 ) {
     let home_path = global_config.home.join(plugin_name);
     if !home_path.exists() {
@@ -39,34 +39,60 @@ pub fn update(
         }
         return;
     }
-    let mustache = mustache::compile_path(path_to_module.join("template.sh"))
-        .expect("Could not parse mustache template");
-
-    let mut toml = read_config(&home_path.join("data.toml")).expect("Cannot find TOML");
+    let mut toml = read_data(&home_path).expect("Cannot find TOML");
     let old_toml = toml.clone();
     let old_config = read_config(&home_path.join("config.toml"))
         .expect("Cannot find old config (maybe you did update terminal-magic)");
     let new_config =
         read_config(&path_to_module.join("config.toml")).expect("module config not found");
-    let mut mustache_map_builder = MapBuilder::new();
+    let unescaped = new_config.plugin_info.unescaped;
+    let mustache = compile_template(git_repo, plugin_name, "template.sh", unescaped);
+    let conflicts = install_conflicts(global_config, plugin_name, &new_config);
+    if !conflicts.is_empty() {
+        for conflict in conflicts {
+            eprintln!("{}", conflict.red());
+        }
+        if fail_on_error {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let mut mustache_map_builder = map_builder();
+    if reconfigure {
+        if let Some(new_placeholders) = &new_config.placeholders {
+            let mut update_map = IndexMap::new();
+            for (key, entry) in new_placeholders {
+                if !entry.is_active(&update_map) {
+                    continue;
+                }
+                println!("Read {}", key);
+                let current = toml.placeholders.as_ref().and_then(|a| a.get(key));
+                update_map.insert(key.to_owned(), prompts::reconfigure(key, entry, current));
+            }
+            toml.placeholders = Some(update_map);
+        } else {
+            toml.placeholders = None;
+        }
+    }
     if old_config != new_config {
         println!("{}", "Config changed check the changes".yellow());
 
-        let old_config_str = toml::to_string(&old_config).unwrap();
-        let new_config_str = toml::to_string(&new_config).unwrap();
+        let old_config_str = to_toml_string(&mask_config(&old_config)).unwrap();
+        let new_config_str = to_toml_string(&mask_config(&new_config)).unwrap();
         print_diff(&old_config_str, &new_config_str);
-        if old_config.placeholders != new_config.placeholders {
+        if !reconfigure && old_config.placeholders != new_config.placeholders {
             let mut update_map = IndexMap::new();
             if let Some(new_placeholders) = &new_config.placeholders {
                 for (key, entry) in new_placeholders {
+                    // drops placeholders which became inactive, asks for those which became active
+                    if !entry.is_active(&update_map) {
+                        continue;
+                    }
                     if let Some(v) = toml.placeholders.as_ref().and_then(|a| a.get(key)) {
                         update_map.insert(key.to_owned(), v.to_owned());
                     } else {
                         // prompt new value
-                        let (new_mustache_map_builder, object) =
-                            read(key, entry, mustache_map_builder);
-                        mustache_map_builder = new_mustache_map_builder;
-                        update_map.insert(key.to_owned(), object);
+                        update_map.insert(key.to_owned(), read(key, entry));
                     }
                 }
                 toml.placeholders = Some(update_map);
@@ -76,6 +102,7 @@ pub fn update(
         }
     }
     toml.plugin_info = new_config.plugin_info.clone();
+    toml.derived = new_config.derived.clone();
 
     if let Some(internal_deps) = toml.plugin_info.internal_dependencies.as_mut() {
         for dep in internal_deps {
@@ -84,7 +111,7 @@ pub fn update(
                 ModuleState::UpToDate => {}
                 ModuleState::NeedsUpdate(reason) => {
                     println!("Update since: {:?}", reason);
-                    update(global_config, git_repo, dep, false, true)
+                    update(global_config, git_repo, dep, false, true, false)
                 }
             }
         }
@@ -101,94 +128,129 @@ pub fn update(
 
     if let Some(placeholders) = toml.placeholders.as_mut() {
         for placeholder in placeholders.iter_mut() {
-            if old_toml
-                .placeholders
-                .as_ref()
-                .and_then(|a| a.get(placeholder.0))
-                .is_some()
-            {
-                if let EntryType::Array(arr) = placeholder.1 {
-                    if !silent && boolean_prompt(&format!("Add new elements [{}]? ", placeholder.0))
+            let key = placeholder.0;
+            let was_declared = old_toml.placeholders.as_ref().and_then(|a| a.get(key)).is_some()
+                && old_config.placeholders.as_ref().and_then(|p| p.get(key)).is_some();
+            if let EntryType::Array(arr) = placeholder.1 {
+                if was_declared
+                    && !silent
+                    && !reconfigure
+                    && !global_config.dry_run
+                    && boolean_prompt(&format!("Add new elements [{}]? ", key))
+                {
+                    // prefer the prototype from the config, it carries the schema
+                    let proto_type = match new_config.placeholders.as_ref().and_then(|a| a.get(key))
                     {
-                        if old_config
-                            .placeholders
-                            .as_ref()
-                            .unwrap()
-                            .get(placeholder.0)
-                            .is_some()
-                        {
-                            let (new_mustache_map_builder, object) =
-                                read_array(placeholder.0, &arr[0], mustache_map_builder);
-                            mustache_map_builder = new_mustache_map_builder;
-                            arr.extend(if let EntryType::Array(a) = object {
-                                a
-                            } else {
-                                unreachable!("read_array MUST always return an EntryType::Array")
-                            });
-                        }
+                        Some(EntryType::Array(proto)) => &proto[0],
+                        _ => &arr[0],
+                    };
+                    let object = read_array(key, proto_type);
+                    arr.extend(if let EntryType::Array(a) = object {
+                        a
                     } else {
-                        let name = get_short_names(arr);
-                        mustache_map_builder = mustache_map_builder
-                            .insert_str(format!("{}_shortNames", placeholder.0), name);
-                    }
+                        unreachable!("read_array MUST always return an EntryType::Array")
+                    });
                 }
             }
-            mustache_map_builder = mustache_map_builder
-                .insert(placeholder.0, &placeholder.1)
-                .expect("Could not parse object");
         }
+        mustache_map_builder =
+            match add_placeholders_as_vars(placeholders, &new_config, mustache_map_builder) {
+                Ok(mustache_map_builder) => mustache_map_builder,
+                Err(err) => {
+                    eprintln!("{}", err.red());
+                    if fail_on_error {
+                        std::process::exit(1);
+                    }
+                    return;
+                }
+            };
+        lint_quoting(git_repo, plugin_name, "template.sh", &new_config, placeholders);
     }
-    let should_overwrite = boolean_prompt("Update supporting files?");
-
     if let Some(files) = &new_config.supporting_files {
-        let home_path = global_config.home.join(plugin_name);
-        mustache_map_builder = add_files_as_vars(
-            files,
-            mustache_map_builder,
-            &home_path,
-            &path_to_module,
-            &home_path,
-            should_overwrite,
-        );
+        mustache_map_builder = add_files_as_vars(files, mustache_map_builder, &home_path);
     }
 
     let mustache_map = mustache_map_builder.build();
-    let script = render(mustache, mustache_map);
+    if !check_strict(global_config, git_repo, plugin_name, &new_config, &mustache_map) {
+        if fail_on_error {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let script = render(mustache, &mustache_map);
     let old_script = get_old_script(global_config, plugin_name);
 
-    print_diff(&old_script, &script);
+    let mut secrets = installed_secret_values(&home_path);
+    secrets.extend(find_secrets(
+        new_config.placeholders.as_ref(),
+        toml.placeholders.as_ref(),
+    ));
+    print_diff(&mask(&old_script, &secrets), &mask(&script, &secrets));
 
-    if !boolean_prompt("Update?") {
+    // a dry run only prints the plan, so it doesn't need a confirmation and can run in scripts
+    if !global_config.dry_run && !boolean_prompt("Update?") {
         return;
     }
-
-    if let PluginType::RustPackage { path, git, tag } = new_config.plugin_info.plugin_type {
-        let mut install_command = Command::new("cargo");
-        if let Some(git) = git {
-            install_command
-                .env("CARGO_NET_GIT_FETCH_WITH_CLI", "true")
-                .arg("install")
-                .arg("--git")
-                .arg(git);
-            if let Some(tag) = tag {
-                install_command.arg("--tag").arg(tag);
-            }
-        } else if let Some(path) = path {
-            install_command
-                .arg("install")
-                .arg("--path")
-                .arg(&path_to_module.join(path));
-        } else {
-            panic!("either path or git should be set");
-        };
-        match install_command
-            .spawn()
-            .expect("Could not install module")
-            .wait_with_output()
+    let mut outputs = vec![];
+    if let Some(files) = &new_config.supporting_files {
+        for (destination, output) in
+            render_outputs(git_repo, plugin_name, files, &home_path, &mustache_map, unescaped)
         {
-            Ok(_) => println!("Successfully installed rust-module"),
-            Err(err) => panic!("{:?}", err),
+            let old_output = std::fs::read_to_string(&destination).unwrap_or_default();
+            if old_output == output {
+                continue;
+            }
+            print_diff(&mask(&old_output, &secrets), &mask(&output, &secrets));
+            if global_config.dry_run
+                || boolean_prompt(&format!("Update {}?", destination.to_string_lossy()))
+            {
+                outputs.push((destination, output));
+            }
         }
     }
-    write_file(global_config, toml, script, plugin_name, &path_to_module);
+
+    if !run_module_hook(
+        global_config,
+        git_repo,
+        plugin_name,
+        &new_config,
+        "pre_update",
+        &mustache_map,
+        &secrets,
+    ) {
+        if fail_on_error {
+            std::process::exit(1);
+        }
+        return;
+    }
+    // only touch the files once the update is confirmed and the pre_update hook passed
+    if let Some(files) = &new_config.supporting_files {
+        write_supporting_files(global_config, files, &path_to_module, &home_path);
+    }
+    if let Err(err) = install_package(
+        global_config,
+        plugin_name,
+        &new_config.plugin_info.plugin_type,
+        &path_to_module,
+    ) {
+        eprintln!("{}", err.red());
+        if fail_on_error {
+            std::process::exit(1);
+        }
+        return;
+    }
+    write_file(global_config, git_repo, toml, script, outputs, plugin_name);
+    save_pre_remove_hook(global_config, git_repo, plugin_name, &new_config, &mustache_map);
+    if !run_module_hook(
+        global_config,
+        git_repo,
+        plugin_name,
+        &new_config,
+        "post_update",
+        &mustache_map,
+        &secrets,
+    ) && fail_on_error
+    {
+        std::process::exit(1);
+    }
 }
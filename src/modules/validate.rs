@@ -0,0 +1,621 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! `validate` checks every module of the git repo, e.g. in CI, and prints `file:line`
+//! diagnostics.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+
+use colored::Colorize;
+use indexmap::IndexMap;
+use mustache::ParserError;
+use regex::Regex;
+use semver::Version;
+
+use crate::{
+    hooks::HOOKS,
+    models::{Derived, EntryType, FileSystemEntry, PluginInfo, PluginType},
+    template::{
+        compile_source, partial_path, read_template, template_tags, templated_files, FILTER_LAMBDAS,
+    },
+};
+
+use super::get_list_of_available_modules;
+
+#[derive(Default)]
+struct Diagnostics {
+    errors: usize,
+    warnings: usize,
+}
+
+impl Diagnostics {
+    fn error(&mut self, file: &Path, line: Option<usize>, message: impl Display) {
+        self.errors += 1;
+        print_diagnostic(file, line, "error".red().bold(), message);
+    }
+
+    fn warning(&mut self, file: &Path, line: Option<usize>, message: impl Display) {
+        self.warnings += 1;
+        print_diagnostic(file, line, "warning".yellow().bold(), message);
+    }
+}
+
+fn print_diagnostic(file: &Path, line: Option<usize>, level: impl Display, message: impl Display) {
+    match line {
+        Some(line) => println!(
+            "{}:{}: {}: {}",
+            file.to_string_lossy(),
+            line,
+            level,
+            message
+        ),
+        None => println!("{}: {}: {}", file.to_string_lossy(), level, message),
+    }
+}
+
+/// The line of the config declaring `key`, either as `key = ...` or as a `[table.key]` header.
+fn key_line(source: &str, key: &str) -> Option<usize> {
+    let quoted = format!("\"{}\"", key);
+    source
+        .lines()
+        .position(|line| {
+            let line = line.trim_start();
+            let assigned = line
+                .strip_prefix(key)
+                .or_else(|| line.strip_prefix(quoted.as_str()))
+                .is_some_and(|rest| rest.trim_start().starts_with('='));
+            assigned || line.contains(&format!(".{}]", key))
+        })
+        .map(|index| index + 1)
+}
+
+/// The first line of the config containing the string `value`.
+fn value_line(source: &str, value: &str) -> Option<usize> {
+    let quoted = format!("\"{}\"", value);
+    source
+        .lines()
+        .position(|line| line.contains(&quoted))
+        .map(|index| index + 1)
+}
+
+/// The fields available inside a section over `entry`.
+fn fields(entry: &EntryType) -> Option<&IndexMap<String, EntryType>> {
+    match entry {
+        EntryType::Array(array) => match array.first() {
+            Some(EntryType::Object(fields)) => Some(fields),
+            _ => None,
+        },
+        EntryType::Object(fields) => Some(fields),
+        _ => None,
+    }
+}
+
+/// Everything a template of the module can refer to, and what was referred to so far.
+struct Names<'a> {
+    placeholders: &'a IndexMap<String, EntryType>,
+    /// Derived values, supporting files and lambdas.
+    others: BTreeSet<String>,
+    used: BTreeSet<String>,
+}
+
+impl<'a> Names<'a> {
+    fn new(config: &'a PluginInfo, placeholders: &'a IndexMap<String, EntryType>) -> Self {
+        let mut others: BTreeSet<String> = FILTER_LAMBDAS.iter().map(|l| l.to_string()).collect();
+        others.insert("join".to_string());
+        others.extend(
+            config
+                .derived
+                .iter()
+                .flatten()
+                .map(|(key, _)| key.to_owned()),
+        );
+        others.extend(file_keys(config.supporting_files.as_ref()));
+        Names {
+            placeholders,
+            others,
+            used: BTreeSet::new(),
+        }
+    }
+
+    /// Looks `name` up in the sections around the tag and then at the top level. `Err` if it
+    /// isn't declared anywhere.
+    fn resolve(
+        &mut self,
+        name: &str,
+        scopes: &[Option<&'a IndexMap<String, EntryType>>],
+    ) -> Result<Option<&'a EntryType>, ()> {
+        let name = name.split('.').next().unwrap_or(name);
+        if name.is_empty() {
+            return Ok(None);
+        }
+        for fields in scopes.iter().rev().flatten() {
+            if let Some(entry) = fields.get(name) {
+                return Ok(Some(entry));
+            }
+        }
+        if let Some(entry) = self.placeholders.get(name) {
+            self.used.insert(name.to_string());
+            return Ok(Some(entry));
+        }
+        if let Some(array) = name.strip_suffix("_shortNames") {
+            if self.placeholders.contains_key(array) {
+                self.used.insert(array.to_string());
+                return Ok(None);
+            }
+        }
+        if self.others.contains(name) {
+            self.used.insert(name.to_string());
+            return Ok(None);
+        }
+        Err(())
+    }
+}
+
+fn file_keys(files: Option<&IndexMap<String, FileSystemEntry>>) -> Vec<String> {
+    let mut keys = vec![];
+    for (key, entry) in files.into_iter().flatten() {
+        keys.push(key.to_owned());
+        if let FileSystemEntry::Directory { files, .. } = entry {
+            keys.extend(file_keys(Some(files)));
+        }
+    }
+    keys
+}
+
+/// Checks that every tag of the template (and the partials it includes) is declared.
+fn check_tags<'a>(
+    diagnostics: &mut Diagnostics,
+    names: &mut Names<'a>,
+    scopes: &mut Vec<Option<&'a IndexMap<String, EntryType>>>,
+    file: &Path,
+    source: &str,
+    (git_repo, plugin_name): (&str, &str),
+    partials: &mut Vec<String>,
+) {
+    let join_source = Regex::new(r"\{\{#\s*join\s*\}\}\s*([^.|{\s]+)").unwrap();
+    for captures in join_source.captures_iter(source) {
+        let line = source[..captures.get(0).unwrap().start()]
+            .matches('\n')
+            .count()
+            + 1;
+        if names.resolve(&captures[1], scopes).is_err() {
+            diagnostics.error(
+                file,
+                Some(line),
+                format!("`{}` is not declared", &captures[1]),
+            );
+        }
+    }
+    let mut in_join = false;
+    for tag in template_tags(source) {
+        let name = tag.name.as_str();
+        match tag.kind {
+            '!' | '=' => {}
+            '>' => {
+                if partials.iter().any(|partial| partial == name) {
+                    continue;
+                }
+                // a missing partial is reported when the template is compiled
+                let path = match partial_path(git_repo, plugin_name, name) {
+                    Some(path) => path,
+                    None => continue,
+                };
+                let partial = std::fs::read_to_string(&path).unwrap_or_default();
+                partials.push(name.to_string());
+                let module = (git_repo, plugin_name);
+                check_tags(
+                    diagnostics,
+                    names,
+                    scopes,
+                    &path,
+                    &partial,
+                    module,
+                    partials,
+                );
+                partials.pop();
+            }
+            '/' => {
+                scopes.pop();
+                if name == "join" {
+                    in_join = false;
+                }
+            }
+            _ if in_join => {}
+            '#' | '^' if FILTER_LAMBDAS.contains(&name) || name == "join" => {
+                in_join = name == "join";
+                scopes.push(None);
+            }
+            '#' | '^' => match names.resolve(name, scopes) {
+                Ok(entry) => scopes.push(entry.and_then(fields)),
+                Err(_) => {
+                    diagnostics.error(file, Some(tag.line), format!("`{}` is not declared", name));
+                    scopes.push(None);
+                }
+            },
+            _ if name == "." => {}
+            _ => {
+                if names.resolve(name, scopes).is_err() {
+                    diagnostics.error(
+                        file,
+                        Some(tag.line),
+                        format!(
+                            "`{}` is not declared in placeholders, derived or supporting_files",
+                            name
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Compiles a template of the module and checks its tags.
+fn check_template<'a>(
+    diagnostics: &mut Diagnostics,
+    names: &mut Names<'a>,
+    (git_repo, plugin_name): (&str, &str),
+    file_name: &str,
+) {
+    let file = Path::new(git_repo).join(plugin_name).join(file_name);
+    let source = std::fs::read_to_string(&file).unwrap_or_default();
+    match read_template(git_repo, plugin_name, file_name) {
+        Ok((expanded, _)) => {
+            // escaping doesn't change whether a template compiles
+            if let Err(err) = compile_source(&expanded, false) {
+                // point at the section the error is about, if it is in this file
+                let section = match &err {
+                    mustache::Error::Parser(ParserError::UnclosedSection(name))
+                    | mustache::Error::Parser(ParserError::EarlySectionClose(name)) => Some(name),
+                    _ => None,
+                };
+                let line = section.and_then(|name| {
+                    template_tags(&source)
+                        .into_iter()
+                        .find(|tag| "#^/".contains(tag.kind) && tag.name == *name)
+                        .map(|tag| tag.line)
+                });
+                diagnostics.error(&file, line, format!("invalid template: {}", err));
+            }
+        }
+        Err(err) => diagnostics.error(&file, None, err),
+    }
+    let module = (git_repo, plugin_name);
+    check_tags(
+        diagnostics,
+        names,
+        &mut vec![],
+        &file,
+        &source,
+        module,
+        &mut vec![],
+    );
+}
+
+fn check_paths(
+    diagnostics: &mut Diagnostics,
+    config_path: &Path,
+    source: &str,
+    path_to_module: &Path,
+    files: &IndexMap<String, FileSystemEntry>,
+) {
+    for (key, entry) in files {
+        let (path, is_dir) = match entry {
+            FileSystemEntry::File { path, .. } => (path, false),
+            FileSystemEntry::Directory {
+                path, files, glob, ..
+            } => {
+                check_paths(diagnostics, config_path, source, path_to_module, files);
+                if let Some(Err(err)) = glob.as_deref().map(glob::Pattern::new) {
+                    diagnostics.error(
+                        config_path,
+                        key_line(source, key),
+                        format!("invalid glob of `{}`: {}", key, err),
+                    );
+                }
+                (path, true)
+            }
+        };
+        let full_path = path_to_module.join(path);
+        let exists = if is_dir {
+            full_path.is_dir()
+        } else {
+            full_path.is_file()
+        };
+        if !exists {
+            diagnostics.error(
+                config_path,
+                key_line(source, key),
+                format!("supporting file `{}` does not exist: {}", key, path),
+            );
+        }
+    }
+}
+
+fn find_cycles(graph: &BTreeMap<String, Vec<String>>) -> Vec<Vec<String>> {
+    fn visit<'a>(
+        module: &'a str,
+        graph: &'a BTreeMap<String, Vec<String>>,
+        done: &mut BTreeSet<&'a str>,
+        path: &mut Vec<&'a str>,
+        cycles: &mut Vec<Vec<String>>,
+    ) {
+        if done.contains(module) {
+            return;
+        }
+        if let Some(start) = path.iter().position(|m| *m == module) {
+            let mut cycle: Vec<String> = path[start..].iter().map(|m| m.to_string()).collect();
+            cycle.push(module.to_string());
+            cycles.push(cycle);
+            return;
+        }
+        path.push(module);
+        for dep in graph.get(module).into_iter().flatten() {
+            visit(dep, graph, done, path, cycles);
+        }
+        path.pop();
+        done.insert(module);
+    }
+    let mut cycles = vec![];
+    let mut done = BTreeSet::new();
+    for module in graph.keys() {
+        visit(module, graph, &mut done, &mut vec![], &mut cycles);
+    }
+    cycles
+}
+
+/// Checks all modules of the git repo and prints the problems. Returns whether there were no
+/// errors.
+pub fn validate(git_repo: &str) -> bool {
+    let mut diagnostics = Diagnostics::default();
+    let modules = get_list_of_available_modules(Path::new(git_repo), git_repo);
+    let mut configs: BTreeMap<String, (PathBuf, String, PluginInfo)> = BTreeMap::new();
+    for module in &modules {
+        let config_path = Path::new(git_repo).join(module).join("config.toml");
+        let source = std::fs::read_to_string(&config_path).unwrap_or_default();
+        match toml::from_str::<PluginInfo>(&source) {
+            Ok(config) => {
+                configs.insert(module.to_owned(), (config_path, source, config));
+            }
+            Err(err) => diagnostics.error(
+                &config_path,
+                err.line_col().map(|(line, _)| line + 1),
+                format!("invalid config: {}", err),
+            ),
+        }
+    }
+
+    let mut graph = BTreeMap::new();
+    let word = Regex::new(r"[A-Za-z0-9_]+").unwrap();
+    for (module, (config_path, source, config)) in &configs {
+        let path_to_module = Path::new(git_repo).join(module);
+        if let Err(err) = Version::parse(&config.plugin_info.version) {
+            diagnostics.error(
+                config_path,
+                key_line(source, "version"),
+                format!("invalid version {}: {}", config.plugin_info.version, err),
+            );
+        }
+        // `new` writes TODO if git has no user.name
+        let author = config.plugin_info.author.trim();
+        if author.is_empty() || author == "TODO" {
+            diagnostics.error(config_path, key_line(source, "author"), "author is not set");
+        }
+        let mut deps = vec![];
+        for dep in config.plugin_info.internal_dependencies.iter().flatten() {
+            if modules.contains(dep) {
+                deps.push(dep.to_owned());
+            } else {
+                diagnostics.error(
+                    config_path,
+                    value_line(source, dep),
+                    format!("internal dependency {} does not exist", dep),
+                );
+            }
+        }
+        graph.insert(module.to_owned(), deps);
+
+        if let Some(files) = &config.supporting_files {
+            check_paths(
+                &mut diagnostics,
+                config_path,
+                source,
+                &path_to_module,
+                files,
+            );
+        }
+        let mut other_paths: Vec<(&str, &String)> = vec![];
+        if let Some(completions) = &config.completions {
+            other_paths.extend(completions.iter());
+        }
+        for hook in &HOOKS {
+            if let Some(path) = config.hooks.as_ref().and_then(|hooks| hooks.get(hook)) {
+                other_paths.push((hook, path));
+            }
+        }
+        if let PluginType::RustPackage {
+            path: Some(path), ..
+        } = &config.plugin_info.plugin_type
+        {
+            other_paths.push(("path", path));
+        }
+        if let PluginType::Prebuilt { platforms, .. } = &config.plugin_info.plugin_type {
+            for (platform, prebuilt) in platforms {
+                let sha256 = prebuilt.sha256.trim();
+                if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                    diagnostics.error(
+                        config_path,
+                        value_line(source, &prebuilt.sha256),
+                        format!("sha256 of {} is not a hex encoded sha256: {}", platform, sha256),
+                    );
+                }
+                match (&prebuilt.path, &prebuilt.url) {
+                    (Some(path), _) => other_paths.push(("path", path)),
+                    (None, Some(url)) => {
+                        if !matches!(
+                            url::Url::parse(url).as_ref().map(url::Url::scheme),
+                            Ok("https") | Ok("file")
+                        ) {
+                            diagnostics.error(
+                                config_path,
+                                value_line(source, url),
+                                format!("url `{}` is neither https:// nor file://", url),
+                            );
+                        }
+                    }
+                    (None, None) => diagnostics.error(
+                        config_path,
+                        key_line(source, platform),
+                        format!("prebuilt source {} needs a path or url", platform),
+                    ),
+                }
+            }
+        }
+        for (key, path) in other_paths {
+            if !path_to_module.join(path).exists() {
+                diagnostics.error(
+                    config_path,
+                    value_line(source, path),
+                    format!("{} `{}` does not exist", key, path),
+                );
+            }
+        }
+
+        let placeholders = config.placeholders.clone().unwrap_or_default();
+        let mut names = Names::new(config, &placeholders);
+        let module_ref = (git_repo, module.as_str());
+        check_template(&mut diagnostics, &mut names, module_ref, "template.sh");
+        for template in
+            templated_files(config.supporting_files.as_ref().unwrap_or(&IndexMap::new()))
+        {
+            if path_to_module.join(&template).is_file() {
+                check_template(&mut diagnostics, &mut names, module_ref, &template);
+            }
+        }
+        for hook in &HOOKS {
+            if let Some(path) = config.hooks.as_ref().and_then(|hooks| hooks.get(hook)) {
+                if path_to_module.join(path).is_file() {
+                    check_template(&mut diagnostics, &mut names, module_ref, path);
+                }
+            }
+        }
+        // placeholders can also be used by derived values and conditions
+        for (key, derived) in config.derived.iter().flatten() {
+            match derived {
+                Derived::Join { source, .. } => {
+                    names.used.insert(source.to_owned());
+                }
+                Derived::Expression { expression } => {
+                    if let Err(err) = compile_source(expression, true) {
+                        diagnostics.error(
+                            config_path,
+                            key_line(source, key),
+                            format!("invalid expression of derived value `{}`: {}", key, err),
+                        );
+                    }
+                    for tag in template_tags(expression) {
+                        let _ = names.resolve(&tag.name, &[]);
+                    }
+                }
+            }
+        }
+        for entry in placeholders.values() {
+            if let EntryType::Typed(placeholder) = entry {
+                for name in word.find_iter(placeholder.when.as_deref().unwrap_or_default()) {
+                    names.used.insert(name.as_str().to_string());
+                }
+            }
+        }
+        for key in placeholders.keys() {
+            if !names.used.contains(key) {
+                diagnostics.warning(
+                    config_path,
+                    key_line(source, key),
+                    format!("placeholder `{}` is never used", key),
+                );
+            }
+        }
+        for key in file_keys(config.supporting_files.as_ref()) {
+            if !names.used.contains(&key) {
+                diagnostics.warning(
+                    config_path,
+                    key_line(source, &key),
+                    format!("supporting file `{}` is never used in a template", key),
+                );
+            }
+        }
+    }
+
+    for cycle in find_cycles(&graph) {
+        let (config_path, source, _) = &configs[&cycle[0]];
+        diagnostics.error(
+            config_path,
+            value_line(source, &cycle[1]),
+            format!("internal dependency cycle: {}", cycle.join(" -> ")),
+        );
+    }
+
+    let summary = format!(
+        "{} modules, {} errors, {} warnings",
+        modules.len(),
+        diagnostics.errors,
+        diagnostics.warnings
+    );
+    if diagnostics.errors > 0 {
+        println!("{}", summary.red());
+    } else {
+        println!("{}", summary.green());
+    }
+    diagnostics.errors == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_each_dependency_cycle_once() {
+        let graph: BTreeMap<String, Vec<String>> = [
+            ("zsh/a", vec!["zsh/b"]),
+            ("zsh/b", vec!["zsh/c"]),
+            ("zsh/c", vec!["zsh/a"]),
+            ("zsh/d", vec!["zsh/a", "zsh/e"]),
+            ("zsh/e", vec!["zsh/e"]),
+            ("zsh/f", vec![]),
+        ]
+        .iter()
+        .map(|(module, deps)| {
+            let deps = deps.iter().map(|dep| dep.to_string()).collect();
+            (module.to_string(), deps)
+        })
+        .collect();
+        assert_eq!(
+            find_cycles(&graph),
+            vec![
+                vec!["zsh/a", "zsh/b", "zsh/c", "zsh/a"],
+                vec!["zsh/e", "zsh/e"],
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_the_line_declaring_a_key() {
+        let source = "[plugin_info]\n\
+                      authority = \"x\"\n\
+                      author = \"TODO\"\n\
+                      \n\
+                      [placeholders]\n\
+                      \"user name\" = \"admin\"\n\
+                      [plugin_info.plugin_type.platforms.x86_64-apple-darwin]\n";
+        assert_eq!(key_line(source, "author"), Some(3));
+        assert_eq!(key_line(source, "user name"), Some(6));
+        assert_eq!(key_line(source, "x86_64-apple-darwin"), Some(7));
+        assert_eq!(key_line(source, "auth"), None);
+        assert_eq!(value_line(source, "TODO"), Some(3));
+    }
+}
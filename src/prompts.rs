@@ -1,9 +1,10 @@
 use async_std::task;
 use indexmap::IndexMap;
-use mustache::MapBuilder;
-use prompts::{confirm::ConfirmPrompt, Prompt, text::TextPrompt};
+use prompts::{confirm::ConfirmPrompt, select::SelectPrompt, text::TextPrompt, Prompt};
 
-use crate::models::EntryType;
+use colored::Colorize;
+
+use crate::models::{EntryType, Placeholder, PlaceholderKind};
 
 // Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
 // 
@@ -20,66 +21,81 @@ pub fn boolean_prompt(prompt_string: &str) -> bool {
 }
 pub fn text_prompt(prompt_string: &str) -> Option<String> {
     let mut prompt = TextPrompt::new(prompt_string);
-    match task::block_on(async { prompt.run().await }) {
-        Ok(val) => val,
-        _ => None,
-    }
+    task::block_on(async { prompt.run().await }).unwrap_or_default()
 }
 pub fn password_prompt(prompt_string: &str) -> Option<String> {
     let mut prompt = TextPrompt::new(prompt_string).with_style(prompts::text::Style::Password);
-    match task::block_on(async { prompt.run().await }) {
-        Ok(val) => val,
-        _ => None,
-    }
+    task::block_on(async { prompt.run().await }).unwrap_or_default()
 }
 
 
-pub fn read(key: &str, entry_type: &EntryType, map_builder: MapBuilder) -> (MapBuilder, EntryType) {
+pub fn read(key: &str, entry_type: &EntryType) -> EntryType {
     match entry_type {
-        EntryType::Value(str) => (map_builder, read_value(key, str)),
-        EntryType::Array(array) => read_array(key, &array[0], map_builder),
-        EntryType::Object(obj) => read_object(obj, map_builder),
+        EntryType::Array(array) => read_array(key, &array[0]),
+        EntryType::Object(obj) => read_object(obj),
+        _ => read_value(key, &Placeholder::from_entry(entry_type), None),
     }
 }
 
-pub fn read_value(key: &str, str: &str) -> EntryType {
-    let mut prompt = TextPrompt::new(format!("{} [{}]? ", key, str));
-    match task::block_on(async { prompt.run().await }) {
-        Ok(Some(s)) => {
-            if !s.is_empty() {
-                EntryType::Value(shellexpand::tilde(&s).to_string())
-            } else {
-                EntryType::Value(shellexpand::tilde(&str).to_string())
+/// Prompts for a scalar placeholder until the input is valid. `current` overrides the default
+/// of the placeholder.
+pub fn read_value(key: &str, placeholder: &Placeholder, current: Option<&EntryType>) -> EntryType {
+    let default = current.or(placeholder.default.as_deref());
+    if let Some(description) = &placeholder.description {
+        println!("{}", description.italic());
+    }
+    match (placeholder.kind, &placeholder.choices) {
+        (PlaceholderKind::Bool, _) => {
+            let mut prompt = ConfirmPrompt::new(format!("{}? ", key));
+            if let Some(EntryType::Bool(initial)) = default {
+                prompt = prompt.set_initial(*initial);
+            }
+            match task::block_on(async { prompt.run().await }) {
+                Ok(Some(val)) => EntryType::Bool(val),
+                _ => std::process::exit(1),
             }
         }
-        _ => std::process::exit(1),
-    }
-}
-
-pub fn get_short_names(array: &[EntryType]) -> String {
-    let mut short_names: Vec<String> = vec![];
-    for entry in array {
-        if let EntryType::Object(obj) = entry {
-            if obj.contains_key("shortName") {
-                if let Some(EntryType::Value(short_name)) = obj.get("shortName") {
-                    short_names.push(short_name.clone());
-                }
+        (PlaceholderKind::Secret, _) => loop {
+            let hint = if default.is_some() { " [********]" } else { "" };
+            let input = match password_prompt(&format!("{}{}? ", key, hint)) {
+                Some(s) if !s.is_empty() => s,
+                Some(_) => default.map(|d| d.to_string()).unwrap_or_default(),
+                None => std::process::exit(1),
+            };
+            match placeholder.parse(&input) {
+                Ok(value) => break value,
+                Err(err) => eprintln!("{}", err.red()),
+            }
+        },
+        (PlaceholderKind::Enum, Some(choices)) if !choices.is_empty() => {
+            let mut choices = choices.clone();
+            if let Some(position) = default.and_then(|d| choices.iter().position(|c| *c == d.to_string())) {
+                let default = choices.remove(position);
+                choices.insert(0, default);
             }
+            EntryType::Value(select_prompt(&format!("{}? ", key), choices))
         }
+        _ => loop {
+            let default = default.map(|d| d.to_string()).unwrap_or_default();
+            let mut prompt = TextPrompt::new(format!("{} [{}]? ", key, default));
+            let input = match task::block_on(async { prompt.run().await }) {
+                Ok(Some(s)) if !s.is_empty() => s,
+                Ok(Some(_)) => default,
+                _ => std::process::exit(1),
+            };
+            match placeholder.parse(&input) {
+                Ok(value) => break value,
+                Err(err) => eprintln!("{}", err.red()),
+            }
+        },
     }
-    short_names.join(" ")
 }
 
-pub fn read_array(
-    key: &str,
-    proto_type: &EntryType,
-    mut map_builder: MapBuilder,
-) -> (MapBuilder, EntryType) {
+pub fn read_array(key: &str, proto_type: &EntryType) -> EntryType {
     let mut new_array = vec![];
     loop {
         let object = proto_type.clone();
-        let (new_map_builder, object_to_insert) = read(key, &object, map_builder);
-        map_builder = new_map_builder;
+        let object_to_insert = read(key, &object);
         new_array.push(object_to_insert);
         let mut prompt = ConfirmPrompt::new("Another one? ");
         match task::block_on(async { prompt.run().await }) {
@@ -87,20 +103,170 @@ pub fn read_array(
             _ => break,
         }
     }
-    let name = get_short_names(&new_array);
-    map_builder = map_builder.insert_str(format!("{}_shortNames", key), name);
-    (map_builder, EntryType::Array(new_array))
+    EntryType::Array(new_array)
 }
 
-pub fn read_object(
-    obj: &IndexMap<String, EntryType>,
-    mut map_builder: MapBuilder,
-) -> (MapBuilder, EntryType) {
+pub fn read_object(obj: &IndexMap<String, EntryType>) -> EntryType {
     let mut new_obj = IndexMap::new();
     for keys in obj.iter() {
-        let (new_map_builder, object) = read(keys.0, keys.1, map_builder);
-        map_builder = new_map_builder;
+        if !keys.1.is_active(&new_obj) {
+            continue;
+        }
+        let object = read(keys.0, keys.1);
         new_obj.insert(keys.0.to_string(), object);
     }
-    (map_builder, EntryType::Object(new_obj))
-}
\ No newline at end of file
+    EntryType::Object(new_obj)
+}
+#[derive(Clone)]
+enum ArrayAction {
+    Edit,
+    Delete,
+    Move,
+    Add,
+    Done,
+}
+
+impl std::fmt::Display for ArrayAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ArrayAction::Edit => "Edit an element",
+            ArrayAction::Delete => "Delete an element",
+            ArrayAction::Move => "Move an element",
+            ArrayAction::Add => "Add an element",
+            ArrayAction::Done => "Done",
+        })
+    }
+}
+
+#[derive(Clone)]
+struct ArrayElement {
+    index: usize,
+    label: String,
+}
+
+impl std::fmt::Display for ArrayElement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] {}", self.index, self.label)
+    }
+}
+
+pub fn select_prompt<T>(prompt_string: &str, choices: Vec<T>) -> T
+where
+    T: Clone + Send + std::fmt::Display,
+{
+    let mut prompt = SelectPrompt::new(prompt_string, choices);
+    match task::block_on(async { prompt.run().await }) {
+        Ok(Some(val)) => val,
+        _ => std::process::exit(1),
+    }
+}
+
+pub fn summarize(entry: &EntryType) -> String {
+    match entry {
+        EntryType::Object(obj) => obj
+            .iter()
+            .map(|(key, val)| format!("{}={}", key, summarize(val)))
+            .collect::<Vec<String>>()
+            .join(", "),
+        EntryType::Array(array) => format!(
+            "[{}]",
+            array
+                .iter()
+                .map(summarize)
+                .collect::<Vec<String>>()
+                .join("; ")
+        ),
+        _ => entry.to_string(),
+    }
+}
+
+fn select_element(prompt_string: &str, array: &[EntryType]) -> usize {
+    let elements = array
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| ArrayElement {
+            index,
+            label: summarize(entry),
+        })
+        .collect();
+    select_prompt(prompt_string, elements).index
+}
+
+/// Asks for `key` again, using the `current` value as default. Falls back to `read` if there is
+/// no current value or its shape does not match the prototype anymore.
+pub fn reconfigure(
+    key: &str,
+    proto_type: &EntryType,
+    current: Option<&EntryType>,
+) -> EntryType {
+    match (proto_type, current) {
+        (EntryType::Array(array), Some(EntryType::Array(current))) => {
+            reconfigure_array(key, &array[0], current)
+        }
+        (EntryType::Object(obj), Some(EntryType::Object(current))) => {
+            reconfigure_object(obj, current)
+        }
+        (EntryType::Array(_) | EntryType::Object(_), _)
+        | (_, None | Some(EntryType::Array(_) | EntryType::Object(_))) => read(key, proto_type),
+        (_, Some(current)) => {
+            read_value(key, &Placeholder::from_entry(proto_type), Some(current))
+        }
+    }
+}
+
+pub fn reconfigure_array(
+    key: &str,
+    proto_type: &EntryType,
+    current: &[EntryType],
+) -> EntryType {
+    let mut new_array = current.to_vec();
+    loop {
+        println!("{} ({} elements):", key, new_array.len());
+        for (index, entry) in new_array.iter().enumerate() {
+            println!("  [{}] {}", index, summarize(entry));
+        }
+        let mut actions = vec![ArrayAction::Add, ArrayAction::Done];
+        if !new_array.is_empty() {
+            actions.splice(0..0, [ArrayAction::Edit, ArrayAction::Delete]);
+        }
+        if new_array.len() > 1 {
+            actions.insert(2, ArrayAction::Move);
+        }
+        match select_prompt(&format!("{}? ", key), actions) {
+            ArrayAction::Edit => {
+                let index = select_element("Which element? ", &new_array);
+                new_array[index] = reconfigure(key, proto_type, Some(&new_array[index]));
+            }
+            ArrayAction::Delete => {
+                let index = select_element("Which element? ", &new_array);
+                new_array.remove(index);
+            }
+            ArrayAction::Move => {
+                let from = select_element("Which element? ", &new_array);
+                let to = select_element("Move to position? ", &new_array);
+                let entry = new_array.remove(from);
+                new_array.insert(to, entry);
+            }
+            ArrayAction::Add => {
+                new_array.push(read(key, proto_type));
+            }
+            ArrayAction::Done => break,
+        }
+    }
+    EntryType::Array(new_array)
+}
+
+pub fn reconfigure_object(
+    obj: &IndexMap<String, EntryType>,
+    current: &IndexMap<String, EntryType>,
+) -> EntryType {
+    let mut new_obj = IndexMap::new();
+    for (key, proto_type) in obj.iter() {
+        if !proto_type.is_active(&new_obj) {
+            continue;
+        }
+        let object = reconfigure(key, proto_type, current.get(key));
+        new_obj.insert(key.to_string(), object);
+    }
+    EntryType::Object(new_obj)
+}
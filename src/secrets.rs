@@ -0,0 +1,258 @@
+// Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
+//
+// This software is released under the MIT License.
+// https://opensource.org/licenses/MIT
+
+//! Values of `secret` placeholders are kept out of `data.toml`. They are stored in a
+//! `secrets.toml` next to it (readable only by the user), which mirrors the shape of the
+//! placeholders but only contains the secret values.
+
+use std::path::Path;
+
+use indexmap::IndexMap;
+
+use crate::models::{EntryType, Placeholder, PlaceholderKind, PluginInfo, Quote};
+
+pub static SECRETS_FILE: &str = "secrets.toml";
+pub static MASK: &str = "********";
+
+fn is_secret(proto: &EntryType) -> bool {
+    matches!(
+        proto,
+        EntryType::Typed(Placeholder {
+            kind: PlaceholderKind::Secret,
+            ..
+        })
+    )
+}
+
+/// Removes the values of secret placeholders from `data` and returns them.
+pub fn extract_secrets(
+    proto: &IndexMap<String, EntryType>,
+    data: &mut IndexMap<String, EntryType>,
+) -> IndexMap<String, EntryType> {
+    let mut secrets = IndexMap::new();
+    for (key, proto) in proto {
+        match (proto, data.get_mut(key)) {
+            (EntryType::Array(array), Some(_)) if array.first().is_some_and(is_secret) => {
+                secrets.insert(key.to_owned(), data.shift_remove(key).unwrap());
+            }
+            (proto, Some(_)) if is_secret(proto) => {
+                secrets.insert(key.to_owned(), data.shift_remove(key).unwrap());
+            }
+            (EntryType::Object(proto), Some(EntryType::Object(data))) => {
+                let nested = extract_secrets(proto, data);
+                if !nested.is_empty() {
+                    secrets.insert(key.to_owned(), EntryType::Object(nested));
+                }
+            }
+            (EntryType::Array(proto), Some(EntryType::Array(data))) => {
+                if let Some(EntryType::Object(proto)) = proto.first() {
+                    let nested: Vec<IndexMap<String, EntryType>> = data
+                        .iter_mut()
+                        .map(|entry| match entry {
+                            EntryType::Object(entry) => extract_secrets(proto, entry),
+                            _ => IndexMap::new(),
+                        })
+                        .collect();
+                    if nested.iter().any(|entry| !entry.is_empty()) {
+                        secrets.insert(
+                            key.to_owned(),
+                            EntryType::Array(nested.into_iter().map(EntryType::Object).collect()),
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    secrets
+}
+
+/// Puts secrets returned by `extract_secrets` back into `data`.
+pub fn merge_secrets(data: &mut IndexMap<String, EntryType>, secrets: IndexMap<String, EntryType>) {
+    for (key, secret) in secrets {
+        match (data.get_mut(&key), secret) {
+            (Some(EntryType::Object(data)), EntryType::Object(secret)) => {
+                merge_secrets(data, secret)
+            }
+            (Some(EntryType::Array(data)), EntryType::Array(secret)) => {
+                for (data, secret) in data.iter_mut().zip(secret) {
+                    if let (EntryType::Object(data), EntryType::Object(secret)) = (data, secret) {
+                        merge_secrets(data, secret);
+                    }
+                }
+            }
+            (_, secret) => {
+                data.insert(key, secret);
+            }
+        }
+    }
+}
+
+pub fn read_secrets(home_path: &Path) -> std::io::Result<IndexMap<String, EntryType>> {
+    let path = home_path.join(SECRETS_FILE);
+    if !path.exists() {
+        return Ok(IndexMap::new());
+    }
+    toml::from_str(&std::fs::read_to_string(path)?)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+}
+
+/// All secret values, so they can be masked before anything is printed.
+pub fn secret_values(secrets: &IndexMap<String, EntryType>) -> Vec<String> {
+    fn collect(entry: &EntryType, values: &mut Vec<String>) {
+        match entry {
+            EntryType::Object(obj) => obj.values().for_each(|e| collect(e, values)),
+            EntryType::Array(array) => array.iter().for_each(|e| collect(e, values)),
+            EntryType::Value(val) if !val.is_empty() => values.push(val.to_owned()),
+            _ => {}
+        }
+    }
+    let mut values = vec![];
+    secrets.values().for_each(|e| collect(e, &mut values));
+    values
+}
+
+/// Replaces every occurrence of the secrets in `text` by `MASK`, also inside longer words and
+/// escaped for single or double quotes like `Quote` does. Longest first, so a secret containing
+/// another one is masked whole.
+pub fn mask(text: &str, secrets: &[String]) -> String {
+    let mut forms: Vec<String> = secrets
+        .iter()
+        .filter(|secret| !secret.is_empty())
+        .flat_map(|secret| {
+            let quoted = [Quote::Single, Quote::Double].map(|quote| {
+                let quoted = quote.apply(secret);
+                quoted[1..quoted.len() - 1].to_string()
+            });
+            std::iter::once(secret.to_owned()).chain(quoted)
+        })
+        .collect();
+    forms.sort_by_key(|form| std::cmp::Reverse(form.len()));
+    forms.dedup();
+    let mut text = text.to_string();
+    for form in forms {
+        text = text.replace(&form, MASK);
+    }
+    text
+}
+
+/// Copy of the placeholder declarations with the defaults of secrets masked, for display.
+pub fn mask_placeholders(
+    placeholders: &IndexMap<String, EntryType>,
+) -> IndexMap<String, EntryType> {
+    fn mask_entry(entry: &EntryType) -> EntryType {
+        match entry {
+            EntryType::Typed(placeholder) if is_secret(entry) && placeholder.default.is_some() => {
+                let mut placeholder = placeholder.clone();
+                placeholder.default = Some(Box::new(EntryType::Value(MASK.to_string())));
+                EntryType::Typed(placeholder)
+            }
+            EntryType::Object(obj) => EntryType::Object(mask_placeholders(obj)),
+            EntryType::Array(array) => EntryType::Array(array.iter().map(mask_entry).collect()),
+            _ => entry.clone(),
+        }
+    }
+    placeholders
+        .iter()
+        .map(|(key, entry)| (key.to_owned(), mask_entry(entry)))
+        .collect()
+}
+
+/// Copy of a module config which is safe to print.
+pub fn mask_config(config: &PluginInfo) -> PluginInfo {
+    PluginInfo {
+        placeholders: config.placeholders.as_ref().map(mask_placeholders),
+        ..config.clone()
+    }
+}
+
+/// Secret values contained in `data` according to the declarations in `proto`.
+pub fn find_secrets(
+    proto: Option<&IndexMap<String, EntryType>>,
+    data: Option<&IndexMap<String, EntryType>>,
+) -> Vec<String> {
+    match (proto, data) {
+        (Some(proto), Some(data)) => secret_values(&extract_secrets(proto, &mut data.clone())),
+        _ => vec![],
+    }
+}
+
+/// Secret values stored for an installed module.
+pub fn installed_secret_values(home_path: &Path) -> Vec<String> {
+    secret_values(&read_secrets(home_path).unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masks_every_occurrence_of_secrets() {
+        let secrets = vec!["7".to_string(), "s3cr3t".to_string(), "s3cr3t-long".to_string()];
+        assert_eq!(
+            mask("export TOKEN=s3cr3t-long PW=s3cr3t ID=xs3cr3t N=1 P=7", &secrets),
+            "export TOKEN=******** PW=******** ID=x******** N=1 P=********"
+        );
+        let secrets = vec![r#"pa$$'w"rd"#.to_string()];
+        assert_eq!(
+            mask(r#"A="pa\$\$'w\"rd" B='pa$$'\''w"rd' C=pa$$'w"rd"#, &secrets),
+            r#"A="********" B='********' C=********"#
+        );
+    }
+
+    #[test]
+    fn extracts_and_merges_nested_secrets() {
+        let table = |source: &str| toml::from_str::<IndexMap<String, EntryType>>(source).unwrap();
+        let proto = table(
+            r#"
+            user = "admin"
+            token = { type = "secret" }
+            [tunnel]
+            host = "localhost"
+            password = { type = "secret" }
+            [[databases]]
+            shortName = "db"
+            password = { type = "secret" }
+            "#,
+        );
+        let original = table(
+            r#"
+            user = "root"
+            token = "t0ken"
+            [tunnel]
+            host = "bastion"
+            password = "tunnel-pw"
+            [[databases]]
+            shortName = "db"
+            password = "db-pw"
+            [[databases]]
+            shortName = "db2"
+            password = "db2-pw"
+            "#,
+        );
+        let mut data = original.clone();
+        let secrets = extract_secrets(&proto, &mut data);
+        assert_eq!(
+            data,
+            table(
+                r#"
+                user = "root"
+                [tunnel]
+                host = "bastion"
+                [[databases]]
+                shortName = "db"
+                [[databases]]
+                shortName = "db2"
+                "#
+            )
+        );
+        assert_eq!(
+            secret_values(&secrets),
+            vec!["t0ken", "tunnel-pw", "db-pw", "db2-pw"]
+        );
+        merge_secrets(&mut data, secrets);
+        assert_eq!(data, original);
+    }
+}
@@ -1,9 +1,17 @@
-use std::path::Path;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
 
+use colored::Colorize;
 use indexmap::IndexMap;
 use mustache::MapBuilder;
+use regex::Regex;
 
-use crate::{models::FileSystemEntry, modules::install::write_supporting_files};
+use crate::{
+    hooks::HOOKS,
+    models::{Derived, EntryType, FileSystemEntry, GlobalConfig, PluginInfo, Quote},
+};
 
 // Copyright (c) 2022 Patrick Amrein <amrein@ubique.ch>
 // 
@@ -12,72 +20,1071 @@ use crate::{models::FileSystemEntry, modules::install::write_supporting_files};
 
 
 
+/// Inserts the destination of every supporting file under its key. Only builds the data, the
+/// files are written by `write_supporting_files`.
 pub fn add_files_as_vars(
     files: &IndexMap<String, FileSystemEntry>,
     mut mustache_map_builder: MapBuilder,
-    home: &Path,
-    path_to_module: &Path,
     cwd: &Path,
-    should_overwrite: bool,
 ) -> MapBuilder {
-    if should_overwrite {
-        write_supporting_files(files, home, path_to_module, cwd);
-    }
     for (place_holder, entry) in files.iter() {
-        match entry {
+        let destination = entry.destination(cwd);
+        mustache_map_builder = mustache_map_builder
+            .insert(place_holder, &destination.to_string_lossy())
+            .expect("Error inserting file placeholder");
+        if let FileSystemEntry::Directory { files, .. } = entry {
+            mustache_map_builder = add_files_as_vars(files, mustache_map_builder, cwd);
+        }
+    }
+    mustache_map_builder
+}
+
+/// Inserts the placeholders, quoted as declared in `config`, and the values derived from them.
+/// Fails if a derived value can't be rendered.
+pub fn add_placeholders_as_vars(
+    placeholders: &IndexMap<String, EntryType>,
+    config: &PluginInfo,
+    mut mustache_map_builder: MapBuilder,
+) -> Result<MapBuilder, String> {
+    let proto = config.placeholders.as_ref();
+    for (key, entry) in placeholders.iter() {
+        mustache_map_builder = mustache_map_builder
+            .insert(key, &quote_entry(proto.and_then(|p| p.get(key)), entry))
+            .expect("Could not parse object");
+    }
+    for (key, value) in derived_values(placeholders, config.derived.as_ref())? {
+        mustache_map_builder = mustache_map_builder.insert_str(key, value);
+    }
+    Ok(mustache_map_builder)
+}
+
+/// Applies the `quote` of the placeholder schema to the value and everything nested in it.
+fn quote_entry(proto: Option<&EntryType>, entry: &EntryType) -> EntryType {
+    match (proto, entry) {
+        (Some(EntryType::Typed(placeholder)), EntryType::Value(_) | EntryType::Int(_))
+            if placeholder.quote != Quote::None =>
+        {
+            EntryType::Value(placeholder.quote.apply(&entry.to_string()))
+        }
+        (Some(EntryType::Object(proto)), EntryType::Object(obj)) => EntryType::Object(
+            obj.iter()
+                .map(|(key, value)| (key.to_owned(), quote_entry(proto.get(key), value)))
+                .collect(),
+        ),
+        (Some(EntryType::Array(proto)), EntryType::Array(array)) => EntryType::Array(
+            array
+                .iter()
+                .map(|value| quote_entry(proto.first(), value))
+                .collect(),
+        ),
+        _ => entry.clone(),
+    }
+}
+
+/// Computes the derived values in declaration order, later ones may use earlier ones.
+pub fn derived_values(
+    placeholders: &IndexMap<String, EntryType>,
+    derived: Option<&IndexMap<String, Derived>>,
+) -> Result<IndexMap<String, String>, String> {
+    let mut values = IndexMap::new();
+    for (key, entry) in placeholders.iter() {
+        if let EntryType::Array(array) = entry {
+            values.insert(
+                format!("{}_shortNames", key),
+                join(array, Some("shortName"), " ", Quote::None),
+            );
+        }
+    }
+    for (key, derived) in derived.into_iter().flatten() {
+        let value = match derived {
+            Derived::Join {
+                source,
+                field,
+                separator,
+                quote,
+            } => match placeholders.get(source) {
+                Some(EntryType::Array(array)) => join(array, field.as_deref(), separator, *quote),
+                _ => String::new(),
+            },
+            Derived::Expression { expression } => {
+                let mut context = placeholders.clone();
+                for (key, value) in values.iter() {
+                    context.insert(key.to_owned(), EntryType::Value(value.to_owned()));
+                }
+                // the template escapes the derived value where it is inserted, if at all
+                compile_source(expression, true)
+                    .and_then(|template| template.render_to_string(&context))
+                    .map_err(|e| format!("Could not render derived value {}: {}", key, e))?
+            }
+        };
+        values.insert(key.to_owned(), value);
+    }
+    Ok(values)
+}
+
+fn join(array: &[EntryType], field: Option<&str>, separator: &str, quote: Quote) -> String {
+    array
+        .iter()
+        .filter_map(|entry| match (entry, field) {
+            (EntryType::Object(obj), Some(field)) => obj.get(field),
+            (EntryType::Object(_), None) => None,
+            (entry, _) => Some(entry),
+        })
+        .map(|entry| quote.apply(&entry.to_string()))
+        .collect::<Vec<String>>()
+        .join(separator)
+}
+
+/// Starts a section wrapped by a filter lambda, followed by the name of the filter.
+const FILTER_START: char = '\u{1}';
+/// Ends the name of the filter.
+const FILTER_NAME_END: char = '\u{2}';
+/// Ends the section wrapped by a filter lambda.
+const FILTER_END: char = '\u{3}';
+/// Ends an element of the array iterated by `join`.
+const JOIN_ELEMENT_END: char = '\u{4}';
+
+/// Lambdas which quote their section for the shell.
+pub static ESCAPING_LAMBDAS: [&str; 2] = ["sh_quote", "sh_dquote"];
+
+/// Lambdas which transform their rendered section, see `apply_filter`.
+pub static FILTER_LAMBDAS: [&str; 10] = [
+    "sh_quote", "sh_dquote", "upper", "lower", "snake", "kebab", "expand", "default", "basename",
+    "dirname",
+];
+
+/// A map builder with the helper lambdas of terminal-magic registered.
+///
+/// Mustache hands lambdas the unrendered section, so they only mark it. The marked text is
+/// transformed once the template is rendered, see `render`.
+pub fn map_builder() -> MapBuilder {
+    FILTER_LAMBDAS
+        .iter()
+        .fold(MapBuilder::new(), |mustache_map_builder, name| {
+            mustache_map_builder.insert_fn(*name, move |section: String| {
+                format!("{}{}{}{}{}", FILTER_START, name, FILTER_NAME_END, section, FILTER_END)
+            })
+        })
+        .insert_fn("join", join_lambda)
+}
+
+/// `{{#join}}databases.shortName|, {{/join}}` iterates the array and marks the end of every
+/// element, the separator is passed along with the filter name. Without a field the elements
+/// themselves are joined, without a separator a space is used.
+fn join_lambda(section: String) -> String {
+    let (path, separator) = section.split_once('|').unwrap_or((&section, " "));
+    let (source, element) = match path.trim().split_once('.') {
+        Some((source, field)) => (source, format!("{{{{& {}}}}}", field)),
+        None => (path.trim(), String::from("{{& .}}")),
+    };
+    format!(
+        "{}join:{}{}{{{{#{}}}}}{}{}{{{{/{}}}}}{}",
+        FILTER_START,
+        separator,
+        FILTER_NAME_END,
+        source,
+        element,
+        JOIN_ELEMENT_END,
+        source,
+        FILTER_END
+    )
+}
+
+/// Splits `text` into lowercase words, at non-alphanumeric characters and camelCase humps.
+fn words(text: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = String::new();
+    let mut previous_lowercase = false;
+    for c in text.chars() {
+        let boundary = !c.is_alphanumeric() || (c.is_uppercase() && previous_lowercase);
+        if boundary && !word.is_empty() {
+            words.push(std::mem::take(&mut word));
+        }
+        if c.is_alphanumeric() {
+            word.extend(c.to_lowercase());
+        }
+        previous_lowercase = c.is_lowercase() || c.is_numeric();
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn apply_filter(filter: &str, text: &str) -> String {
+    let (name, argument) = filter.split_once(':').unwrap_or((filter, ""));
+    match name {
+        "sh_quote" => Quote::Single.apply(text),
+        "sh_dquote" => Quote::Double.apply(text),
+        "upper" => text.to_uppercase(),
+        "lower" => text.to_lowercase(),
+        "snake" => words(text).join("_"),
+        "kebab" => words(text).join("-"),
+        "expand" => shellexpand::full(text)
+            .map(|expanded| expanded.into_owned())
+            .unwrap_or_else(|_| text.to_string()),
+        // `{{#default}}{{editor}}|vim{{/default}}`
+        "default" => match text.rsplit_once('|') {
+            Some(("", default)) => default.to_string(),
+            Some((value, _)) => value.to_string(),
+            None => text.to_string(),
+        },
+        "basename" => Path::new(text)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        "dirname" => Path::new(text)
+            .parent()
+            .map(|parent| parent.to_string_lossy().into_owned())
+            .unwrap_or_default(),
+        "join" => {
+            let mut elements: Vec<&str> = text.split(JOIN_ELEMENT_END).collect();
+            elements.pop();
+            elements.join(argument)
+        }
+        _ => text.to_string(),
+    }
+}
+
+/// Replaces the marked sections, innermost first.
+fn apply_filters(mut rendered: String) -> String {
+    while let Some(start) = rendered.rfind(FILTER_START) {
+        let name_end = match rendered[start..].find(FILTER_NAME_END) {
+            Some(offset) => start + offset,
+            None => break,
+        };
+        let end = match rendered[name_end..].find(FILTER_END) {
+            Some(offset) => name_end + offset,
+            None => break,
+        };
+        let filtered = apply_filter(
+            &rendered[start + 1..name_end],
+            &rendered[name_end + 1..end],
+        );
+        rendered.replace_range(start..end + 1, &filtered);
+    }
+    rendered
+}
+
+pub fn render(mustache: mustache::Template, mustache_map: &mustache::Data) -> String {
+    let rendered = mustache
+        .render_data_to_string(mustache_map)
+        .expect("Could not render mustache template");
+    apply_filters(rendered)
+}
+
+/// Renders the supporting files marked with `template = true`, paired with their destination.
+pub fn render_outputs(
+    git_repo: &str,
+    plugin_name: &str,
+    files: &IndexMap<String, FileSystemEntry>,
+    cwd: &Path,
+    mustache_map: &mustache::Data,
+    unescaped: bool,
+) -> Vec<(PathBuf, String)> {
+    let mut outputs = vec![];
+    for file in files.values() {
+        let destination = file.destination(cwd);
+        match file {
             FileSystemEntry::File {
-                version: _version,
                 path,
-                destination,
+                template: true,
+                ..
             } => {
-                let destination = if let Some(destination) = destination {
-                    destination
-                        .to_owned()
-                        .parse()
-                        .expect("Could not parse path")
-                } else {
-                    cwd.join(path)
+                let mustache = compile_template(git_repo, plugin_name, path, unescaped);
+                outputs.push((destination, render(mustache, mustache_map)));
+            }
+            FileSystemEntry::File { .. } => {}
+            FileSystemEntry::Directory { files, .. } => outputs.extend(render_outputs(
+                git_repo,
+                plugin_name,
+                files,
+                &destination,
+                mustache_map,
+                unescaped,
+            )),
+        }
+    }
+    outputs
+}
+
+/// Turns `{{name}}` into `{{& name}}`, for modules which set `unescaped`.
+fn raw_tags(source: &str) -> String {
+    let re = Regex::new(r"\{\{\{[^}]*\}\}\}|\{\{\s*(?P<name>[^#^/>!=&{}\s][^}]*?)\s*\}\}").unwrap();
+    re.replace_all(source, |captures: &regex::Captures| match captures.name("name") {
+        Some(name) => format!("{{{{& {}}}}}", name.as_str()),
+        None => captures[0].to_string(),
+    })
+    .into_owned()
+}
+
+/// Compiles a template source like `compile_template`, with the partials already expanded.
+pub fn compile_source(source: &str, unescaped: bool) -> Result<mustache::Template, mustache::Error> {
+    if unescaped {
+        mustache::compile_str(&raw_tags(source))
+    } else {
+        mustache::compile_str(source)
+    }
+}
+
+/// A tag of a template: `kind` is the sigil (`#`, `^`, `/`, `>`, `!`, `&`, `{`) or a space for a
+/// variable, `line` is 1-based.
+pub struct TemplateTag {
+    pub line: usize,
+    pub kind: char,
+    pub name: String,
+}
+
+/// All tags of the template source, in order.
+pub fn template_tags(source: &str) -> Vec<TemplateTag> {
+    let re = Regex::new(r"\{\{\{\s*(?P<raw>[^}]*?)\s*\}\}\}|\{\{\s*(?P<kind>[#^/>!=&]?)\s*(?P<name>[^}]*?)\s*\}\}")
+        .unwrap();
+    re.captures_iter(source)
+        .map(|captures| {
+            let start = captures.get(0).unwrap().start();
+            let line = source[..start].matches('\n').count() + 1;
+            match captures.name("raw") {
+                Some(raw) => TemplateTag {
+                    line,
+                    kind: '{',
+                    name: raw.as_str().to_string(),
+                },
+                None => TemplateTag {
+                    line,
+                    kind: captures["kind"].chars().next().unwrap_or(' '),
+                    name: captures["name"].to_string(),
+                },
+            }
+        })
+        .collect()
+}
+
+/// Looks `name` up like mustache does: the first part from the innermost context defining it,
+/// the rest through the nested maps. A context holds every element of the array it iterates.
+fn lookup<'a>(
+    name: &str,
+    contexts: &[Option<Vec<&'a mustache::Data>>],
+) -> Vec<&'a mustache::Data> {
+    let mut parts = name.split('.');
+    let first = parts.next().unwrap_or_default();
+    let field = |data: &'a mustache::Data, key: &str| match data {
+        mustache::Data::Map(map) => map.get(key),
+        _ => None,
+    };
+    let mut values: Vec<&mustache::Data> = contexts
+        .iter()
+        .rev()
+        .flatten()
+        .map(|context| {
+            context
+                .iter()
+                .filter_map(|data| field(data, first))
+                .collect::<Vec<_>>()
+        })
+        .find(|values| !values.is_empty())
+        .unwrap_or_default();
+    for part in parts {
+        values = values.iter().filter_map(|data| field(data, part)).collect();
+    }
+    values
+}
+
+/// Every key declared in the placeholder schema, nested ones included. Placeholders whose `when`
+/// doesn't hold have no value but are still declared.
+fn declared_names(placeholders: Option<&IndexMap<String, EntryType>>) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let mut pending: Vec<&IndexMap<String, EntryType>> = placeholders.into_iter().collect();
+    while let Some(placeholders) = pending.pop() {
+        for (key, entry) in placeholders.iter() {
+            names.insert(key.to_owned());
+            match entry {
+                EntryType::Object(obj) => pending.push(obj),
+                EntryType::Array(array) => {
+                    if let Some(EntryType::Object(obj)) = array.first() {
+                        pending.push(obj);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    names
+}
+
+/// The tags of the template `file_name` which neither the data nor the placeholder schema
+/// define, as `file:line: undefined variable|section`. Partials are checked with the same data.
+pub fn undefined_tags(
+    git_repo: &str,
+    plugin_name: &str,
+    file_name: &str,
+    declared: &HashSet<String>,
+    mustache_map: &mustache::Data,
+) -> Vec<String> {
+    let path = Path::new(git_repo).join(plugin_name).join(file_name);
+    let source = std::fs::read_to_string(&path).unwrap_or_default();
+    let mut undefined = vec![];
+    let mut contexts = vec![Some(vec![mustache_map])];
+    check_defined(
+        (git_repo, plugin_name),
+        (file_name, &source),
+        declared,
+        &mut contexts,
+        &mut vec![],
+        &mut undefined,
+    );
+    undefined
+}
+
+/// `undefined_tags` of `template.sh` and every supporting file marked with `template = true`.
+fn undefined_module_tags(
+    git_repo: &str,
+    plugin_name: &str,
+    config: &PluginInfo,
+    mustache_map: &mustache::Data,
+) -> Vec<String> {
+    let declared = declared_names(config.placeholders.as_ref());
+    let mut undefined =
+        undefined_tags(git_repo, plugin_name, "template.sh", &declared, mustache_map);
+    let files = config.supporting_files.as_ref();
+    let mut pending: Vec<&IndexMap<String, FileSystemEntry>> = files.into_iter().collect();
+    while let Some(files) = pending.pop() {
+        for file in files.values() {
+            match file {
+                FileSystemEntry::File {
+                    path,
+                    template: true,
+                    ..
+                } => undefined.extend(undefined_tags(
+                    git_repo,
+                    plugin_name,
+                    path,
+                    &declared,
+                    mustache_map,
+                )),
+                FileSystemEntry::File { .. } => {}
+                FileSystemEntry::Directory { files, .. } => pending.push(files),
+            }
+        }
+    }
+    undefined
+}
+
+/// Prints the undefined tags of the module and returns `false` if there are any. `config` is the
+/// module config with the placeholder schema, not the answers. Always passes with `--no-strict`.
+pub fn check_strict(
+    global_config: &GlobalConfig,
+    git_repo: &str,
+    plugin_name: &str,
+    config: &PluginInfo,
+    mustache_map: &mustache::Data,
+) -> bool {
+    if !global_config.strict {
+        return true;
+    }
+    let undefined = undefined_module_tags(git_repo, plugin_name, config, mustache_map);
+    for tag in undefined.iter() {
+        eprintln!("[{}] {}", plugin_name.yellow(), tag.red());
+    }
+    if !undefined.is_empty() {
+        eprintln!(
+            "{}",
+            "Templates use undefined values, fix the module or pass --no-strict".red()
+        );
+    }
+    undefined.is_empty()
+}
+
+fn is_rendered(contexts: &[Option<Vec<&mustache::Data>>]) -> bool {
+    contexts.iter().all(Option::is_some)
+}
+
+fn check_defined(
+    (git_repo, plugin_name): (&str, &str),
+    (file_name, source): (&str, &str),
+    declared: &HashSet<String>,
+    contexts: &mut Vec<Option<Vec<&mustache::Data>>>,
+    partials: &mut Vec<String>,
+    undefined: &mut Vec<String>,
+) {
+    for tag in template_tags(source) {
+        let name = tag.name.as_str();
+        match tag.kind {
+            '!' | '=' => {}
+            '>' => {
+                // a missing or recursive partial fails when the template is compiled
+                let path = match partial_path(git_repo, plugin_name, name) {
+                    Some(path) if !partials.iter().any(|partial| partial == name) => path,
+                    _ => continue,
                 };
+                let partial = std::fs::read_to_string(&path).unwrap_or_default();
+                let label = path.strip_prefix(git_repo).unwrap_or(&path).to_string_lossy();
+                partials.push(name.to_string());
+                check_defined(
+                    (git_repo, plugin_name),
+                    (&label, &partial),
+                    declared,
+                    contexts,
+                    partials,
+                    undefined,
+                );
+                partials.pop();
+            }
+            '/' => {
+                contexts.pop();
+            }
+            // `default` is there for values which aren't set
+            '#' if name == "default" => contexts.push(None),
+            '#' | '^' => {
+                let values = lookup(name, contexts);
+                let is_declared = name.split('.').all(|part| declared.contains(part));
+                if values.is_empty() && is_declared {
+                    // an inactive placeholder is falsy, its fields are declared too
+                    contexts.push(Some(vec![]));
+                    continue;
+                }
+                if values.is_empty() && name != "." && is_rendered(contexts) {
+                    undefined.push(format!(
+                        "{}:{}: undefined section `{}`",
+                        file_name, tag.line, name
+                    ));
+                }
+                // the elements of an array are the context of the section
+                let context: Vec<&mustache::Data> = values
+                    .iter()
+                    .flat_map(|data| match data {
+                        mustache::Data::Vec(elements) if tag.kind == '#' => {
+                            elements.iter().collect()
+                        }
+                        mustache::Data::Map(_) if tag.kind == '#' => vec![*data],
+                        _ => vec![],
+                    })
+                    .collect();
+                let is_array = |data: &&mustache::Data| matches!(data, mustache::Data::Vec(_));
+                // an empty array is never iterated, anything goes inside
+                let never_rendered = tag.kind == '#'
+                    && !values.is_empty()
+                    && values.iter().all(is_array)
+                    && context.is_empty();
+                contexts.push(if never_rendered { None } else { Some(context) });
+            }
+            _ if name == "." => {}
+            _ => {
+                if lookup(name, contexts).is_empty()
+                    && !name.split('.').all(|part| declared.contains(part))
+                    && is_rendered(contexts)
+                {
+                    undefined.push(format!(
+                        "{}:{}: undefined variable `{}`",
+                        file_name, tag.line, name
+                    ));
+                }
+            }
+        }
+    }
+}
 
-                mustache_map_builder = mustache_map_builder
-                    .insert(place_holder, &destination.to_string_lossy())
-                    .expect("Error inserting file placeholder");
+/// Prints a warning for every tag inside a quoted string of the template whose value contains
+/// characters the quotes don't protect against. Values with a `quote` mode and sections wrapped
+/// in an escaping lambda are fine.
+pub fn lint_quoting(
+    git_repo: &str,
+    plugin_name: &str,
+    file_name: &str,
+    config: &PluginInfo,
+    placeholders: &IndexMap<String, EntryType>,
+) {
+    let source = match read_template(git_repo, plugin_name, file_name) {
+        Ok((source, _)) => source,
+        Err(_) => return,
+    };
+    let tag = Regex::new(r"\{\{\{?\s*(?P<sigil>[#^/&]?)\s*(?P<name>[^}\s]+)\s*\}?\}\}").unwrap();
+    let mut escaped: usize = 0;
+    for (number, line) in source.lines().enumerate() {
+        let mut quote = None;
+        let mut cursor = 0;
+        for captures in tag.captures_iter(line) {
+            let matched = captures.get(0).unwrap();
+            quote = scan_quotes(&line[cursor..matched.start()], quote);
+            cursor = matched.end();
+            let name = &captures["name"];
+            let is_escaping = ESCAPING_LAMBDAS.contains(&name);
+            match &captures["sigil"] {
+                "#" if is_escaping => escaped += 1,
+                // a stray closing tag must not hide the warnings after it
+                "/" if is_escaping => escaped = escaped.saturating_sub(1),
+                "" | "&" if escaped == 0 => {
+                    let quote = match quote {
+                        Some(quote) => quote,
+                        None => continue,
+                    };
+                    let unsafe_chars: &[char] = if quote == '\'' {
+                        &['\'']
+                    } else {
+                        &['"', '$', '`', '\\']
+                    };
+                    let mut values = vec![];
+                    collect_unquoted_values(
+                        name,
+                        config.placeholders.as_ref(),
+                        Some(placeholders),
+                        &mut values,
+                    );
+                    if let Some(value) = values.iter().find(|v| v.contains(unsafe_chars)) {
+                        let value = value.chars().find(|c| unsafe_chars.contains(c)).unwrap();
+                        eprintln!(
+                            "{} {}:{}: value of {{{{{}}}}} contains {:?} inside a {} string, set `quote` on the placeholder or use {{{{#sh_quote}}}}",
+                            "[lint]".yellow(),
+                            file_name,
+                            number + 1,
+                            name,
+                            value,
+                            if quote == '\'' { "single-quoted" } else { "double-quoted" },
+                        );
+                    }
+                }
+                _ => {}
             }
-            FileSystemEntry::Directory {
-                version: _version,
-                destination,
-                path,
-                files,
-            } => {
-                let destination = if let Some(destination) = destination {
-                    destination
-                        .to_owned()
-                        .parse()
-                        .expect("Could not parse path")
-                } else {
-                    cwd.join(path)
+        }
+    }
+}
+
+/// The shell quote still open after `text`, starting with `quote` open.
+fn scan_quotes(text: &str, mut quote: Option<char>) -> Option<char> {
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => quote = None,
+            (Some('"') | None, '\\') => {
+                chars.next();
+            }
+            (None, '\'' | '"') => quote = Some(c),
+            _ => {}
+        }
+    }
+    quote
+}
+
+/// Collects the values of every placeholder named `name` which has no `quote` mode.
+fn collect_unquoted_values(
+    name: &str,
+    proto: Option<&IndexMap<String, EntryType>>,
+    data: Option<&IndexMap<String, EntryType>>,
+    values: &mut Vec<String>,
+) {
+    for (key, entry) in data.into_iter().flatten() {
+        let proto = proto.and_then(|p| p.get(key));
+        match (proto, entry) {
+            (_, EntryType::Object(obj)) => {
+                let proto = match proto {
+                    Some(EntryType::Object(proto)) => Some(proto),
+                    _ => None,
+                };
+                collect_unquoted_values(name, proto, Some(obj), values);
+            }
+            (_, EntryType::Array(array)) => {
+                let proto = match proto {
+                    Some(EntryType::Array(proto)) => proto.first(),
+                    _ => None,
                 };
+                for element in array {
+                    match (proto, element) {
+                        (Some(EntryType::Object(proto)), EntryType::Object(obj)) => {
+                            collect_unquoted_values(name, Some(proto), Some(obj), values)
+                        }
+                        (_, EntryType::Object(obj)) => {
+                            collect_unquoted_values(name, None, Some(obj), values)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            (Some(EntryType::Typed(placeholder)), _) if placeholder.quote != Quote::None => {}
+            _ if key == name => values.push(entry.to_string()),
+            _ => {}
+        }
+    }
+}
 
-                mustache_map_builder = mustache_map_builder
-                    .insert(place_holder, &destination.to_string_lossy())
-                    .expect("Error inserting file placeholder");
-                mustache_map_builder = add_files_as_vars(
-                    files,
-                    mustache_map_builder,
-                    home,
-                    path_to_module,
-                    cwd,
-                    should_overwrite,
-                );
+pub static PARTIALS_DIR: &str = "partials";
+pub static PARTIALS_FILE: &str = "partials.toml";
+
+/// Compiles a template of the module. `{{> name}}` partials are resolved from the module
+/// directory first and then from the `partials/` directory at the root of the git repo. Values
+/// are HTML escaped unless the module is `unescaped`.
+pub fn compile_template(
+    git_repo: &str,
+    plugin_name: &str,
+    file_name: &str,
+    unescaped: bool,
+) -> mustache::Template {
+    let (source, _) =
+        read_template(git_repo, plugin_name, file_name).expect("Could not read mustache template");
+    compile_source(&source, unescaped).expect("Could not parse mustache template")
+}
+
+/// The template source with all partials expanded, and the sources of the partials used.
+pub fn read_template(
+    git_repo: &str,
+    plugin_name: &str,
+    file_name: &str,
+) -> std::io::Result<(String, IndexMap<String, String>)> {
+    let path_to_module = Path::new(git_repo).join(plugin_name);
+    let source = std::fs::read_to_string(path_to_module.join(file_name))?;
+    let dirs = [path_to_module, Path::new(git_repo).join(PARTIALS_DIR)];
+    let mut partials = IndexMap::new();
+    let source = expand_partials(&source, &dirs, &mut vec![], &mut partials)?;
+    Ok((source, partials))
+}
+
+/// The sources and partials of the template, the templated supporting files and the hooks.
+/// Recorded on install to find out whether the rendered files changed.
+pub fn read_template_sources(
+    git_repo: &str,
+    plugin_name: &str,
+    config: &PluginInfo,
+) -> std::io::Result<IndexMap<String, String>> {
+    let path_to_module = Path::new(git_repo).join(plugin_name);
+    let mut templates = vec!["template.sh".to_string()];
+    templates.extend(templated_files(
+        config.supporting_files.as_ref().unwrap_or(&IndexMap::new()),
+    ));
+    if let Some(hooks) = &config.hooks {
+        templates.extend(HOOKS.iter().filter_map(|hook| hooks.get(hook)).cloned());
+    }
+    let mut sources = IndexMap::new();
+    for path in templates {
+        let source = std::fs::read_to_string(path_to_module.join(&path))?;
+        let (_, partials) = read_template(git_repo, plugin_name, &path)?;
+        sources.insert(path, source);
+        sources.extend(partials);
+    }
+    Ok(sources)
+}
+
+/// The templated supporting files, relative to the module.
+pub fn templated_files(files: &IndexMap<String, FileSystemEntry>) -> Vec<String> {
+    let mut templates = vec![];
+    for entry in files.values() {
+        match entry {
+            FileSystemEntry::File {
+                path,
+                template: true,
+                ..
+            } => templates.push(path.to_owned()),
+            FileSystemEntry::File { .. } => {}
+            FileSystemEntry::Directory { files, .. } => templates.extend(templated_files(files)),
+        }
+    }
+    templates
+}
+
+/// The template sources recorded when the module was installed.
+pub fn read_installed_sources(home_path: &Path) -> IndexMap<String, String> {
+    std::fs::read_to_string(home_path.join(PARTIALS_FILE))
+        .ok()
+        .and_then(|partials| toml::from_str(&partials).ok())
+        .unwrap_or_default()
+}
+
+/// The file the partial `name` of the module is read from.
+pub fn partial_path(git_repo: &str, plugin_name: &str, name: &str) -> Option<PathBuf> {
+    let path_to_module = Path::new(git_repo).join(plugin_name);
+    find_partial(name, &[path_to_module, Path::new(git_repo).join(PARTIALS_DIR)])
+}
+
+fn find_partial(name: &str, dirs: &[PathBuf]) -> Option<PathBuf> {
+    dirs.iter()
+        .flat_map(|dir| [dir.join(format!("{}.sh", name)), dir.join(name)])
+        .find(|path| path.is_file())
+}
+
+fn expand_partials(
+    source: &str,
+    dirs: &[PathBuf],
+    stack: &mut Vec<String>,
+    partials: &mut IndexMap<String, String>,
+) -> std::io::Result<String> {
+    // a partial alone on its line is indented like the tag, as mustache does
+    let re = Regex::new(
+        r"(?m)^(?P<indent>[ \t]*)\{\{>\s*(?P<standalone>[^}\s]+)\s*\}\}[ \t]*(\r?\n|$)|\{\{>\s*(?P<inline>[^}\s]+)\s*\}\}",
+    )
+    .unwrap();
+    let mut result = String::new();
+    let mut cursor = 0;
+    for captures in re.captures_iter(source) {
+        let tag = captures.get(0).unwrap();
+        result.push_str(&source[cursor..tag.start()]);
+        cursor = tag.end();
+        let (name, indent) = match captures.name("standalone") {
+            Some(name) => (name.as_str(), Some(&captures["indent"])),
+            None => (&captures["inline"], None),
+        };
+        if stack.iter().any(|partial| partial == name) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Partial {} includes itself", name),
+            ));
+        }
+        let path = find_partial(name, dirs).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Could not find partial {}", name),
+            )
+        })?;
+        let content = std::fs::read_to_string(path)?;
+        stack.push(name.to_string());
+        let expanded = expand_partials(&content, dirs, stack, partials)?;
+        stack.pop();
+        partials.insert(name.to_string(), content);
+        match indent {
+            Some(indent) => {
+                for line in expanded.lines() {
+                    if !line.is_empty() {
+                        result.push_str(indent);
+                    }
+                    result.push_str(line);
+                    result.push('\n');
+                }
             }
+            None => result.push_str(&expanded),
         }
     }
-    mustache_map_builder
+    result.push_str(&source[cursor..]);
+    Ok(result)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn undefined(schema: &str, data: mustache::Data, source: &str) -> Vec<String> {
+        let schema: IndexMap<String, EntryType> = toml::from_str(schema).unwrap();
+        let mut undefined = vec![];
+        check_defined(
+            ("/nonexistent", "zsh/test"),
+            ("template.sh", source),
+            &declared_names(Some(&schema)),
+            &mut vec![Some(vec![&data])],
+            &mut vec![],
+            &mut undefined,
+        );
+        undefined
+    }
+
+    #[test]
+    fn reports_undefined_variables_and_sections() {
+        let data = map_builder().insert_str("containerName", "web").build();
+        assert_eq!(
+            undefined(
+                "containerName = \"web\"",
+                data,
+                "docker start {{contianerName}}\n{{#enabled}}x{{/enabled}}\n{{containerName}}"
+            ),
+            vec![
+                "template.sh:1: undefined variable `contianerName`",
+                "template.sh:2: undefined section `enabled`",
+            ]
+        );
+    }
+
+    #[test]
+    fn inactive_placeholders_are_declared() {
+        let schema = r#"
+            engine = "mysql"
+            pg_schema = { type = "string", when = "engine == postgres" }
+        "#;
+        let data = map_builder().insert_str("engine", "mysql").build();
+        let source = "{{#pg_schema}}SET search_path={{pg_schema}} {{typo}}{{/pg_schema}}\n\
+                      {{^pg_schema}}{{engine}}{{/pg_schema}} {{pg_schema}}";
+        assert_eq!(
+            undefined(schema, data, source),
+            vec!["template.sh:1: undefined variable `typo`"]
+        );
+    }
+
+    #[test]
+    fn checks_array_sections_against_their_elements() {
+        let schema = r#"
+            [[databases]]
+            shortName = "db"
+            port = "5432"
+        "#;
+        let databases: IndexMap<String, EntryType> = toml::from_str(schema).unwrap();
+        let data = map_builder()
+            .insert("databases", &databases["databases"])
+            .unwrap()
+            .insert_vec("empty", |builder| builder)
+            .build();
+        let source = "{{#databases}}{{shortName}} {{prot}}{{/databases}}\n\
+                      {{#empty}}{{anything}}{{/empty}} {{#default}}{{editor}}|vim{{/default}}";
+        assert_eq!(
+            undefined(schema, data, source),
+            vec!["template.sh:1: undefined variable `prot`"]
+        );
+    }
+
+    #[test]
+    fn records_the_template_templated_files_hooks_and_their_partials() {
+        let repo = std::env::temp_dir().join(format!("tm-sources-{}", std::process::id()));
+        let module = repo.join("zsh/test");
+        std::fs::create_dir_all(module.join("files")).unwrap();
+        std::fs::create_dir_all(repo.join(PARTIALS_DIR)).unwrap();
+        std::fs::write(module.join("template.sh"), "{{> header}}\n").unwrap();
+        std::fs::write(module.join("files/app.conf"), "{{> footer}}\n").unwrap();
+        std::fs::write(module.join("files/plain"), "plain\n").unwrap();
+        std::fs::write(repo.join(PARTIALS_DIR).join("header.sh"), "# header\n").unwrap();
+        std::fs::write(repo.join(PARTIALS_DIR).join("footer.sh"), "# footer\n").unwrap();
+        std::fs::write(module.join("remove.sh"), "rm {{> footer}}\n").unwrap();
+        let config: PluginInfo = toml::from_str(
+            r#"
+            [plugin_info]
+            author = "a"
+            version = "1.0.0"
+            plugin_type = "shell"
+
+            [supporting_files]
+            conf = { version = "1", path = "files/app.conf", template = true }
+            plain = { version = "1", path = "files/plain" }
+
+            [hooks]
+            pre_remove = "remove.sh"
+            "#,
+        )
+        .unwrap();
+        let sources = read_template_sources(repo.to_str().unwrap(), "zsh/test", &config).unwrap();
+        let _ = std::fs::remove_dir_all(&repo);
+        assert_eq!(
+            sources.into_iter().collect::<Vec<_>>(),
+            vec![
+                ("template.sh".to_string(), "{{> header}}\n".to_string()),
+                ("header".to_string(), "# header\n".to_string()),
+                ("files/app.conf".to_string(), "{{> footer}}\n".to_string()),
+                ("footer".to_string(), "# footer\n".to_string()),
+                ("remove.sh".to_string(), "rm {{> footer}}\n".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_open_shell_quotes() {
+        assert_eq!(scan_quotes("echo \"", None), Some('"'));
+        assert_eq!(scan_quotes("echo 'it\"s", None), Some('\''));
+        assert_eq!(scan_quotes("echo \\\"", None), None);
+        assert_eq!(scan_quotes("\\\" still open", Some('"')), Some('"'));
+        assert_eq!(scan_quotes("' '", Some('\'')), Some('\''));
+        assert_eq!(scan_quotes("done\" \"", Some('"')), Some('"'));
+    }
+
+    #[test]
+    fn renders_tags_unescaped_only_if_asked_to() {
+        assert_eq!(
+            raw_tags("{{name}} {{{raw}}} {{& amp}} {{#section}}{{/section}} {{> partial}}"),
+            "{{& name}} {{{raw}}} {{& amp}} {{#section}}{{/section}} {{> partial}}"
+        );
+        let data = map_builder().insert_str("value", "a<b & \"c\"").build();
+        let render_source =
+            |unescaped| render(compile_source("echo {{value}}", unescaped).unwrap(), &data);
+        assert_eq!(render_source(true), "echo a<b & \"c\"");
+        assert_eq!(render_source(false), "echo a&lt;b &amp; &quot;c&quot;");
+    }
+
+    #[test]
+    fn expands_partials_from_the_module_before_the_repo() {
+        let repo = std::env::temp_dir().join(format!("tm-partials-{}", std::process::id()));
+        let dirs = [repo.join("zsh/test"), repo.join(PARTIALS_DIR)];
+        for dir in &dirs {
+            std::fs::create_dir_all(dir).unwrap();
+        }
+        std::fs::write(dirs[0].join("greeting.sh"), "hi").unwrap();
+        std::fs::write(dirs[1].join("greeting.sh"), "hello").unwrap();
+        std::fs::write(dirs[1].join("fn.sh"), "f() {\n  {{> body}}\n}\n").unwrap();
+        std::fs::write(dirs[1].join("body"), "echo {{> greeting}}\n").unwrap();
+        std::fs::write(dirs[1].join("loop.sh"), "{{> loop}}").unwrap();
+        let expand = |source: &str| {
+            let mut partials = IndexMap::new();
+            expand_partials(source, &dirs, &mut vec![], &mut partials)
+                .map(|expanded| (expanded, partials.keys().cloned().collect::<Vec<_>>()))
+        };
+        let expanded = expand("if true; then\n    {{> fn}}\nfi\n{{>greeting}}!");
+        let missing = expand("{{> missing}}").map_err(|err| err.kind());
+        let cycle = expand("{{> loop}}").map_err(|err| err.kind());
+        let _ = std::fs::remove_dir_all(&repo);
+        assert_eq!(
+            expanded.unwrap(),
+            (
+                "if true; then\n    f() {\n      echo hi\n    }\nfi\nhi!".to_string(),
+                vec!["greeting".to_string(), "body".to_string(), "fn".to_string()]
+            )
+        );
+        assert_eq!(missing, Err(std::io::ErrorKind::NotFound));
+        assert_eq!(cycle, Err(std::io::ErrorKind::InvalidData));
+    }
+
+    #[test]
+    fn splits_words_at_separators_and_humps() {
+        assert_eq!(words("dockerComposeV2"), vec!["docker", "compose", "v2"]);
+        assert_eq!(words("Docker compose-file_v2"), vec!["docker", "compose", "file", "v2"]);
+        assert!(words(" -_ ").is_empty());
+    }
+
+    #[test]
+    fn applies_filter_lambdas() {
+        let data = map_builder()
+            .insert_str("app", "myCoolApp")
+            .insert_str("editor", "")
+            .insert_str("tool", "/usr/local/bin/tool")
+            .insert_str("quote", "it's")
+            .insert_vec("databases", |databases| {
+                databases
+                    .push_map(|db| db.insert_str("shortName", "db"))
+                    .push_map(|db| db.insert_str("shortName", "db2"))
+            })
+            .insert_vec("tags", |tags| tags.push_str("a").push_str("b"))
+            .build();
+        for (source, expected) in [
+            ("{{#upper}}{{app}}{{/upper}}", "MYCOOLAPP"),
+            ("{{#snake}}{{app}}{{/snake}}", "my_cool_app"),
+            ("{{#kebab}}{{app}}{{/kebab}}", "my-cool-app"),
+            ("{{#upper}}{{#kebab}}{{app}}{{/kebab}}{{/upper}}", "MY-COOL-APP"),
+            ("{{#default}}{{editor}}|vim{{/default}}", "vim"),
+            ("{{#default}}{{app}}|vim{{/default}}", "myCoolApp"),
+            ("{{#basename}}{{tool}}{{/basename}}", "tool"),
+            ("{{#dirname}}{{tool}}{{/dirname}}", "/usr/local/bin"),
+            ("{{#sh_quote}}{{quote}}{{/sh_quote}}", "'it'\\''s'"),
+            ("{{#join}}databases.shortName|, {{/join}}", "db, db2"),
+            ("{{#join}}tags{{/join}}", "a b"),
+        ] {
+            let template = compile_source(source, true).unwrap();
+            assert_eq!(render(template, &data), expected, "{}", source);
+        }
+    }
 
-pub fn render(mustache: mustache::Template, mustache_map: mustache::Data) -> String {
-    mustache
-        .render_data_to_string(&mustache_map)
-        .expect("Could not render mustache template")
-}
\ No newline at end of file
+    #[test]
+    fn derives_values_and_reports_broken_expressions() {
+        let placeholders: IndexMap<String, EntryType> = toml::from_str(
+            r#"
+            host = "localhost"
+            [[databases]]
+            shortName = "db"
+            port = "5432"
+            "#,
+        )
+        .unwrap();
+        let derived = |source: &str| {
+            let derived: IndexMap<String, Derived> = toml::from_str(source).unwrap();
+            derived_values(&placeholders, Some(&derived))
+        };
+        let values = derived(
+            r#"
+            ports = { source = "databases", field = "port", separator = "," }
+            conn = { expression = "{{host}}:{{ports}} {{databases_shortNames}}" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(values["conn"], "localhost:5432 db");
+        assert_eq!(
+            derived(r#"conn = { expression = "{{#host}}" }"#),
+            Err("Could not render derived value conn: found an unclosed section".to_string())
+        );
+    }
+}